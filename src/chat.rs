@@ -1,40 +1,281 @@
 use base64::Engine;
 use poise::serenity_prelude::futures::StreamExt;
 use poise::serenity_prelude::{
-    self as serenity, json, Attachment, CacheHttp, EditMessage, Message, User,
+    self as serenity, Attachment, CacheHttp, ChannelId, ComponentInteraction,
+    CreateInteractionResponse, CreateInteractionResponseFollowup, CreateThread, GetMessages,
+    GuildId, Mentionable, Message, MessageId, Reaction, ReactionType, User, UserId,
 };
+use poise::CreateReply;
 use regex::Regex;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::env::var;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
 
 use base64::prelude::BASE64_STANDARD;
+use chrono::TimeZone;
+use chrono_tz::Asia::Tokyo;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::Document;
 
-use crate::Error;
+use crate::db::Db;
+use crate::document::{self, extract_text, is_document, DocumentError};
+use crate::image::{self, ImageError};
+use crate::knowledge::Knowledge;
+use crate::llm::{
+    build_provider, complete_text, estimate_tokens, trim_to_budget, ChatMessage, CompletionRequest,
+    Part, Provider, ProviderError, Role, StreamEvent, ToolCall, Usage, IMAGE_TOKENS,
+};
+use crate::models::{build_models, Models};
+use crate::reply::{Output, BUTTON_PREFIX, MAX_MESSAGE_LENGTH};
+use crate::split::{split_message, truncate};
+use crate::sse::CompletionError;
+use crate::tools::Tools;
+use crate::{Context, Error};
 
-const MAX_MESSAGE_LENGTH: usize = 2000;
+const MAX_TOOL_ROUNDS: usize = 5;
+const DEFAULT_CONTEXT_TOKENS: usize = 8000;
+const DEFAULT_IMAGE_MAX_DIMENSION: u32 = 1568;
+/// Channel messages included by `history:` without a count
+const DEFAULT_HISTORY_MESSAGES: u8 = 20;
+/// The most messages Discord returns at once
+const MAX_HISTORY_MESSAGES: u8 = 100;
+/// Room left in the context for the summary of dropped messages
+const SUMMARY_TOKENS: usize = 500;
+const MAX_REPLY_CHAIN: usize = 100;
+/// An option must be followed by a space, so that e.g. `history:2024年` is
+/// left as the question
+const OPTION_PATTERN: &str = r"^(?:model:(\S+)|history:(\d*))(?:\s+|$)";
+/// Edits are throttled to stay under Discord's rate limits, but a large
+/// pending chunk is shown sooner
+const FLUSH_INTERVAL: Duration = Duration::from_millis(1500);
+const MIN_FLUSH_INTERVAL: Duration = Duration::from_millis(700);
+const FLUSH_SIZE: usize = 400;
+/// Replies older than these can no longer be regenerated
+const MAX_GENERATIONS: usize = 200;
+/// Translations remembered to skip repeated flags; older messages may be
+/// translated again
+const MAX_TRANSLATIONS: usize = 1000;
+const SUMMARY_PROMPT: &str = "Summarize the following conversation in a few sentences, keeping the facts and requests needed to continue it. Answer in the language of the conversation.";
+const CHANNEL_SUMMARY_PROMPT: &str = "Summarize the following Discord conversation for someone catching up. List the main topics, key decisions and open questions as short bullet points. Cite the messages supporting each point by their numbers in square brackets, e.g. [3]. Answer in the language of the conversation.";
+const TRANSLATION_PROMPT: &str = "Reply with the translation only, keeping the formatting, mentions, URLs and emoji as they are.";
+const DEFAULT_TRANSLATION: &str =
+    "If the following text is in Japanese, translate it into English. Otherwise translate it into Japanese.";
+/// Languages of the flag reactions that trigger a translation
+const FLAG_LANGUAGES: &[(&str, &str)] = &[
+    ("JP", "Japanese"),
+    ("US", "English"),
+    ("GB", "English"),
+    ("AU", "English"),
+    ("CA", "English"),
+    ("CN", "Simplified Chinese"),
+    ("TW", "Traditional Chinese"),
+    ("KR", "Korean"),
+    ("FR", "French"),
+    ("DE", "German"),
+    ("ES", "Spanish"),
+    ("MX", "Spanish"),
+    ("IT", "Italian"),
+    ("PT", "Portuguese"),
+    ("BR", "Portuguese"),
+    ("RU", "Russian"),
+    ("VN", "Vietnamese"),
+    ("TH", "Thai"),
+    ("ID", "Indonesian"),
+];
+/// The most messages fetched by /summarize
+const MAX_SUMMARY_MESSAGES: usize = 500;
+const KNOWLEDGE_PROMPT: &str = "Past messages of this server that may be relevant. When you use one, cite it with its Markdown link as given.";
+const STOPPED_MESSAGE: &str = "Stopped.";
+/// Shorter than the chat handler timeout in main.rs, so that a slow reply is
+/// finished with an error instead of being cancelled halfway
+const GENERATION_TIMEOUT: Duration = Duration::from_secs(240);
+const MAX_ERROR_LENGTH: usize = 200;
+const DEFAULT_SYSTEM_PROMPT: &str = "あなたはDiscordの内輪コミュニティで使用されているアシスタントbotです。質問に対しては簡潔な回答を心掛けてください。";
 
 pub struct Chat {
     http_client: Arc<reqwest::Client>,
     bot: Arc<User>,
+    db: Arc<Db>,
+    tools: Tools,
+    knowledge: Option<Arc<Knowledge>>,
+    provider: Box<dyn Provider>,
     mention_pattern: Regex,
+    option_pattern: Regex,
+    citation_pattern: Regex,
+    models: Models,
+    context_tokens: usize,
+    summarize: bool,
+    image_max_dimension: u32,
+    user_daily_tokens: Option<u64>,
+    guild_daily_tokens: Option<u64>,
+    generations: Mutex<HashMap<u64, Generation>>,
+    /// Threads started by /thread_chat, so that other messages are skipped
+    /// without a query
+    threads: Mutex<HashSet<ChannelId>>,
+    translations: Translations,
+}
+
+/// The messages translated by flag reactions and their languages, so that
+/// a message is translated once per language even when flags are added at
+/// the same time
+#[derive(Default)]
+struct Translations(Mutex<BTreeSet<(MessageId, &'static str)>>);
+
+impl Translations {
+    /// Whether the translation was not claimed before
+    fn claim(&self, message_id: MessageId, language: &'static str) -> bool {
+        let mut translations = self.0.lock().expect("translations lock poisoned");
+        let claimed = translations.insert((message_id, language));
+
+        // message ids are snowflakes, so the first is the oldest
+        while translations.len() > MAX_TRANSLATIONS {
+            translations.pop_first();
+        }
+
+        claimed
+    }
+
+    /// Lets the translation be tried again after a failure
+    fn release(&self, message_id: MessageId, language: &'static str) {
+        self.0
+            .lock()
+            .expect("translations lock poisoned")
+            .remove(&(message_id, language));
+    }
+}
+
+/// What is needed to stop or regenerate a reply
+#[derive(Clone)]
+struct Generation {
+    origin: Origin,
     model: String,
-    api_url: String,
-    token: String,
+    messages: Vec<ChatMessage>,
+    ephemeral: bool,
+    /// Set while the reply is streamed
+    stop: Option<watch::Sender<bool>>,
+    /// The messages of a long reply after the first one
+    extra_messages: Vec<(ChannelId, MessageId)>,
+}
+
+/// Marks the generation as finished even if the reply is cancelled, so that
+/// it can still be regenerated
+struct GenerationGuard<'a> {
+    generations: &'a Mutex<HashMap<u64, Generation>>,
+    key: u64,
+}
+
+impl Drop for GenerationGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(generation) = self
+            .generations
+            .lock()
+            .expect("generations lock poisoned")
+            .get_mut(&self.key)
+        {
+            generation.stop = None;
+        }
+    }
+}
+
+/// One-off options written before a message, e.g. `model:fast history:20`
+#[derive(Debug, Default, PartialEq)]
+struct Options<'a> {
+    model: Option<&'a str>,
+    /// How many channel messages to include as context
+    history: Option<u8>,
+}
+
+#[derive(poise::ChoiceParameter)]
+pub enum ModelScope {
+    User,
+    Channel,
+    Server,
+}
+
+/// The asking user and where the conversation takes place
+#[derive(Clone)]
+pub struct Origin {
+    pub user_id: UserId,
+    /// Nickname in the server or display name
+    pub user_name: String,
+    pub channel_id: ChannelId,
+    pub guild_id: Option<GuildId>,
 }
 
-pub fn build_chat(http_client: Arc<reqwest::Client>, bot: Arc<User>) -> Result<Chat, Error> {
+impl Origin {
+    async fn from_message(ctx: &serenity::Context, message: &serenity::Message) -> Origin {
+        Origin {
+            user_id: message.author.id,
+            user_name: message
+                .author_nick(ctx)
+                .await
+                .unwrap_or(message.author.display_name().to_owned()),
+            channel_id: message.channel_id,
+            guild_id: message.guild_id,
+        }
+    }
+
+    async fn from_command(ctx: Context<'_>) -> Origin {
+        Origin {
+            user_id: ctx.author().id,
+            user_name: match ctx.author_member().await {
+                Some(member) => member.display_name().to_owned(),
+                None => ctx.author().display_name().to_owned(),
+            },
+            channel_id: ctx.channel_id(),
+            guild_id: ctx.guild_id(),
+        }
+    }
+}
+
+pub async fn build_chat(
+    http_client: Arc<reqwest::Client>,
+    bot: Arc<User>,
+    db: Arc<Db>,
+    tools: Tools,
+    knowledge: Option<Arc<Knowledge>>,
+) -> Result<Chat, Error> {
+    let mut threads = HashSet::new();
+    for thread in db.get_threads().await? {
+        threads.insert(ChannelId::new(thread.get_str("thread_id")?.parse()?));
+    }
     let mention_pattern = Regex::new(&format!("<@{}>[\\s　]*", bot.id))?;
-    let model = var("LLM_MODEL").unwrap_or(String::from(""));
-    let api_url = var("LLM_API_URL").unwrap_or(String::from(""));
-    let token = var("LLM_TOKEN").unwrap_or(String::from(""));
+    let option_pattern = Regex::new(OPTION_PATTERN)?;
 
     Ok(Chat {
-        http_client,
         bot,
+        db,
+        tools,
+        knowledge,
+        provider: build_provider(Arc::clone(&http_client))?,
+        http_client,
         mention_pattern,
-        model,
-        api_url,
-        token,
+        option_pattern,
+        citation_pattern: Regex::new(r"\[(\d+)\]")?,
+        models: build_models(),
+        context_tokens: match var("LLM_CONTEXT_TOKENS") {
+            Ok(context_tokens) => context_tokens.parse()?,
+            Err(_) => DEFAULT_CONTEXT_TOKENS,
+        },
+        summarize: var("LLM_SUMMARIZE").is_ok_and(|summarize| summarize == "true"),
+        image_max_dimension: match var("LLM_IMAGE_MAX_DIMENSION") {
+            Ok(image_max_dimension) => image_max_dimension.parse()?,
+            Err(_) => DEFAULT_IMAGE_MAX_DIMENSION,
+        },
+        user_daily_tokens: var("LLM_USER_DAILY_TOKENS")
+            .ok()
+            .map(|tokens| tokens.parse())
+            .transpose()?,
+        guild_daily_tokens: var("LLM_GUILD_DAILY_TOKENS")
+            .ok()
+            .map(|tokens| tokens.parse())
+            .transpose()?,
+        generations: Mutex::new(HashMap::new()),
+        threads: Mutex::new(threads),
+        translations: Translations::default(),
     })
 }
 
@@ -49,177 +290,2282 @@ impl Chat {
             return Ok(());
         }
 
-        if !message.mentions_user(Arc::as_ref(&self.bot)) {
+        // every message in a thread started by /thread_chat is part of the conversation
+        let in_thread = !message.author.bot && self.is_thread(message.channel_id);
+
+        if !in_thread && !message.mentions_user(Arc::as_ref(&self.bot)) {
             return Ok(());
         }
 
-        let mut chain = vec![];
-        self.get_reply_chain(ctx, message, &mut chain).await?;
+        let origin = Origin::from_message(ctx, message).await;
 
-        let system_message = json::json!({
-            "role": "system",
-            "content": "あなたはDiscordの内輪コミュニティで使用されているアシスタントbotです。質問に対しては簡潔な回答を心掛けてください。",
-        });
-        let messages = chain
-            .iter()
-            .map(|m| async {
-                if m.author.id == self.bot.id {
-                    json::json!({
-                        "role": "assistant",
-                        "content": m.content,
-                    })
-                } else {
-                    let urls = futures::future::try_join_all(
-                        m.attachments
-                            .iter()
-                            .filter(|a| {
-                                a.height.is_some() && a.width.is_some() && a.content_type.is_some()
-                            })
-                            .map(|a| self.get_image_base64(a)),
-                    )
-                    .await;
-                    let mut content = vec![json::json!({
-                        "type": "text",
-                        "text": self.delete_mention_to_myself(m),
-                    })];
-                    if let Ok(urls) = urls {
-                        content.extend(urls.into_iter().map(|url| {
-                            json::json!({
-                                "type": "image_url",
-                                "image_url": {
-                                    "url": url,
-                                }
-                            })
-                        }));
-                    }
+        let options = self.get_options(message);
 
-                    json::json!({
-                        "role": "user",
-                        "content": content,
-                    })
+        let model = match options.model {
+            Some(name) => match self.models.resolve(name) {
+                Some(model) => model.to_owned(),
+                None => {
+                    message
+                        .reply(
+                            ctx,
+                            format!("Unknown model {name}.\n{}", self.models.describe()),
+                        )
+                        .await?;
+                    return Ok(());
                 }
-            })
-            .collect::<Vec<_>>();
-        let messages = futures::future::join_all(messages).await;
-        let messages = vec![system_message]
-            .into_iter()
-            .chain(messages)
-            .collect::<Vec<json::Value>>();
-
-        let chat_completion_url = format!("{}/chat/completions", self.api_url);
-        let mut headers = reqwest::header::HeaderMap::new();
-        headers.insert(
-            reqwest::header::AUTHORIZATION,
-            format!("Bearer {}", self.token).parse()?,
-        );
-        headers.insert(reqwest::header::CONTENT_TYPE, "application/json".parse()?);
-
-        let mut response = self
-            .http_client
-            .post(&chat_completion_url)
-            .headers(headers)
-            .body(
-                json::json!({
-                    "model": self.model,
-                    "messages": messages,
-                    "stream": true,
-                })
-                .to_string(),
+            },
+            None => self.get_preferred_model(&origin).await?,
+        };
+
+        if let Some(refusal) = self.check_quota(&origin).await? {
+            message.reply(ctx, refusal).await?;
+            return Ok(());
+        }
+
+        let mut messages = vec![ChatMessage::text(
+            Role::System,
+            self.get_system_prompt(ctx, &origin).await?,
+        )];
+        messages.extend(
+            self.get_knowledge(
+                ctx,
+                &origin,
+                &self.delete_mention_to_myself(message),
+                Some(message.id),
+                true,
             )
-            .send()
-            .await?
-            .bytes_stream();
+            .await,
+        );
 
-        let mut my_message = Message::default();
-        let mut count = MAX_MESSAGE_LENGTH;
-        let mut reply = String::new();
+        // attachments of the question that could not be sent
+        let mut skipped = vec![];
+        if in_thread {
+            messages.extend(self.get_thread_history(message.channel_id).await?);
+            // stored first so that the question is kept even if the reply fails
+            self.db
+                .add_thread_message(
+                    message.channel_id.get(),
+                    message.id.get(),
+                    "user",
+                    &self.delete_mention_to_myself(message),
+                )
+                .await?;
+            let (chat_message, errors) = self.to_chat_message(message).await;
+            messages.push(chat_message);
+            skipped = errors;
+        } else {
+            let chain = self.get_reply_chain(ctx, message).await?;
 
-        let mut stream_buffer: Vec<u8> = Vec::new();
-        let mut reply_buffer = String::new();
+            let history = match options.history {
+                Some(history) => history,
+                None => self
+                    .db
+                    .get_channel_history(message.channel_id.get())
+                    .await?
+                    .unwrap_or(0),
+            };
+            if history > 0 {
+                messages.extend(self.get_channel_history(ctx, &chain, history).await?);
+            }
+
+            let chat_messages =
+                futures::future::join_all(chain.iter().map(|m| self.to_chat_message(m))).await;
+            for (chat_message, errors) in chat_messages {
+                messages.push(chat_message);
+                // the last one is the question; the others were reported
+                // when they were asked
+                skipped = errors;
+            }
+        }
+
+        if !skipped.is_empty() {
+            let notice = skipped
+                .iter()
+                .map(describe_error)
+                .collect::<Vec<_>>()
+                .join("\n");
+            message.reply(ctx, notice).await?;
+        }
 
-        while let Some(bytes) = response.next().await {
-            let bytes = bytes?.clone();
+        let messages = self.fit_context(&origin, &model, messages).await;
 
-            let chunks = bytes.split(|&x| x == b'\n').collect::<Vec<&[u8]>>();
-            let mut done = false;
+        let mut output = Output::message(message);
 
-            for chunk in chunks {
-                if chunk.is_empty() || chunk == b"[DONE]" {
-                    continue;
+        let reply = self
+            .complete(ctx, &origin, &model, messages, &mut output)
+            .await?;
+
+        if in_thread {
+            self.db
+                .add_thread_message(
+                    message.channel_id.get(),
+                    message.id.get(),
+                    "assistant",
+                    &reply,
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Keeps the most recent messages within the context budget, replacing
+    /// the dropped ones with a summary if LLM_SUMMARIZE is enabled
+    async fn fit_context(
+        &self,
+        origin: &Origin,
+        model: &str,
+        mut messages: Vec<ChatMessage>,
+    ) -> Vec<ChatMessage> {
+        let budget = if self.summarize {
+            self.context_tokens.saturating_sub(SUMMARY_TOKENS)
+        } else {
+            self.context_tokens
+        };
+
+        let mut dropped = trim_to_budget(&mut messages, budget);
+        if !self.summarize || dropped.is_empty() {
+            return messages;
+        }
+
+        // the dropped messages may not fit in the context either
+        trim_to_budget(&mut dropped, self.context_tokens);
+        let transcript = dropped
+            .iter()
+            .map(|message| {
+                let role = match message.role {
+                    Role::Assistant => "assistant",
+                    Role::Tool => "tool",
+                    _ => "user",
+                };
+                format!("{role}: {}", message.joined_text())
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let summary = complete_text(
+            self.provider.as_ref(),
+            &CompletionRequest {
+                model,
+                messages: &[
+                    ChatMessage::text(Role::System, SUMMARY_PROMPT),
+                    ChatMessage::text(Role::User, transcript),
+                ],
+                tools: &[],
+            },
+        )
+        .await;
+
+        match summary {
+            Ok((summary, usage)) => {
+                self.record_usage(origin, model, usage).await;
+
+                // some APIs accept only one system message
+                let summary = format!("\nSummary of the earlier conversation:\n{summary}");
+                match messages
+                    .first_mut()
+                    .filter(|message| message.role == Role::System)
+                {
+                    Some(system) => system.content.push(Part::Text(summary)),
+                    None => messages.insert(0, ChatMessage::text(Role::System, summary)),
                 }
-                if !chunk.starts_with(b"data: ") {
-                    continue;
+            }
+            Err(e) => log::warn!("Failed to summarize the dropped messages: {e}"),
+        }
+
+        messages
+    }
+
+    fn is_thread(&self, channel_id: ChannelId) -> bool {
+        self.threads
+            .lock()
+            .expect("threads lock poisoned")
+            .contains(&channel_id)
+    }
+
+    async fn get_thread_history(&self, thread_id: ChannelId) -> Result<Vec<ChatMessage>, Error> {
+        self.db
+            .get_thread_messages(thread_id.get())
+            .await?
+            .iter()
+            .map(|message| {
+                let role = match message.get_str("role")? {
+                    "assistant" => Role::Assistant,
+                    _ => Role::User,
+                };
+                Ok(ChatMessage::text(role, message.get_str("content")?))
+            })
+            .collect()
+    }
+
+    /// Runs the completion and the tool calls it requests, rendering the
+    /// reply into the output. Returns the text of the reply.
+    async fn complete(
+        &self,
+        ctx: &serenity::Context,
+        origin: &Origin,
+        model: &str,
+        messages: Vec<ChatMessage>,
+        output: &mut Output<'_>,
+    ) -> Result<String, Error> {
+        let (stop_sender, mut stop) = watch::channel(false);
+        self.start_generation(
+            output.key(),
+            Generation {
+                origin: origin.clone(),
+                model: model.to_owned(),
+                messages: messages.clone(),
+                ephemeral: output.is_ephemeral(),
+                stop: Some(stop_sender),
+                extra_messages: vec![],
+            },
+        );
+        let _guard = GenerationGuard {
+            generations: &self.generations,
+            key: output.key(),
+        };
+
+        let typing = output.start_typing(ctx);
+        let reply = self
+            .generate(ctx, origin, model, messages, &mut stop, output)
+            .await;
+        drop(typing);
+
+        let my_messages = match output.messages(ctx).await {
+            Ok(my_messages) => my_messages,
+            Err(e) => {
+                log::warn!("Failed to get the sent messages: {e}");
+                vec![]
+            }
+        };
+        self.finish_generation(output.key(), &my_messages);
+
+        let reply = reply?;
+        for my_message in &my_messages {
+            self.db
+                .add_reply(
+                    my_message.id.get(),
+                    my_message.channel_id.get(),
+                    origin.user_id.get(),
+                    model,
+                )
+                .await?;
+        }
+
+        Ok(reply)
+    }
+
+    async fn generate(
+        &self,
+        ctx: &serenity::Context,
+        origin: &Origin,
+        model: &str,
+        mut messages: Vec<ChatMessage>,
+        stop: &mut watch::Receiver<bool>,
+        output: &mut Output<'_>,
+    ) -> Result<String, Error> {
+        let tools = self.tools.definitions();
+        let mut reply = String::new();
+        // the reply so far is kept when the generation times out
+        let result = tokio::time::timeout(GENERATION_TIMEOUT, async {
+            for round in 0..MAX_TOOL_ROUNDS {
+                // the last round is sent without tools so the model has to answer
+                let tools: &[_] = if round + 1 == MAX_TOOL_ROUNDS {
+                    &[]
                 } else {
-                    stream_buffer.extend_from_slice(&chunk[6..]); // Remove "data: " prefix
-                }
-                if let Ok(piece) = json::from_slice::<json::Value>(&stream_buffer) {
-                    stream_buffer.clear();
-                    reply_buffer.push_str(
-                        piece["choices"][0]["delta"]["content"]
-                            .as_str()
-                            .unwrap_or(""),
-                    );
-                    if let Some(finish_reason) = piece["choices"][0]["finish_reason"].as_str() {
-                        if finish_reason == "stop" || finish_reason == "length" {
-                            done = true;
+                    &tools
+                };
+
+                let mut usage = None;
+                let tool_calls = self
+                    .stream_reply(
+                        ctx,
+                        &CompletionRequest {
+                            model,
+                            messages: &messages,
+                            tools,
+                        },
+                        &mut reply,
+                        &mut usage,
+                        stop,
+                        output,
+                    )
+                    .await;
+
+                let tool_calls = match tool_calls {
+                    Ok(tool_calls) => tool_calls,
+                    Err(e) => {
+                        // the tokens of a failed stream may still be billed
+                        if let Some(usage) = usage {
+                            self.record_usage(origin, model, usage).await;
                         }
+                        return Err(self.show_error(ctx, &reply, e, output).await);
                     }
+                };
+                self.record_usage(origin, model, usage.unwrap_or_default())
+                    .await;
+
+                if tool_calls.is_empty() {
+                    break;
+                }
+
+                messages.push(ChatMessage::tool_calls(tool_calls.clone()));
+
+                for tool_call in tool_calls {
+                    let result = self
+                        .tools
+                        .call(ctx, origin, &tool_call.name, &tool_call.arguments)
+                        .await;
+
+                    messages.push(ChatMessage::tool_result(tool_call.id, result));
                 }
             }
 
-            if done || reply_buffer.chars().count() >= 100 {
-                if (count + reply_buffer.chars().count()) >= MAX_MESSAGE_LENGTH {
-                    reply = reply_buffer.clone();
-                    my_message = message.reply(ctx, &reply).await?;
-                    count = reply.chars().count();
-                } else {
-                    reply.push_str(&reply_buffer);
-                    my_message
-                        .edit(ctx, EditMessage::new().content(&reply))
+            if reply.trim().is_empty() {
+                if *stop.borrow() {
+                    output.render(ctx, STOPPED_MESSAGE, true).await?;
+                    return Ok(());
+                }
+
+                let e = ProviderError::Empty.into();
+                return Err(self.show_error(ctx, &reply, e, output).await);
+            }
+
+            output.render(ctx, &reply, true).await?;
+
+            Ok::<(), Error>(())
+        })
+        .await;
+
+        match result {
+            Ok(result) => result.map(|()| reply),
+            Err(elapsed) => Err(self.show_error(ctx, &reply, elapsed.into(), output).await),
+        }
+    }
+
+    /// Appends a concise explanation of the error to the reply and returns
+    /// the error, whose details are logged by the event handler
+    async fn show_error(
+        &self,
+        ctx: &serenity::Context,
+        reply: &str,
+        e: Error,
+        output: &mut Output<'_>,
+    ) -> Error {
+        let notice = describe_error(&e);
+        let content = if reply.trim().is_empty() {
+            notice
+        } else {
+            format!("{reply}\n\n{notice}")
+        };
+
+        if let Err(render_error) = output.render(ctx, &content, true).await {
+            log::warn!("Failed to show the error: {render_error}");
+        }
+
+        e
+    }
+
+    fn start_generation(&self, key: u64, generation: Generation) {
+        let mut generations = self.generations.lock().expect("generations lock poisoned");
+        generations.insert(key, generation);
+
+        // keys are snowflakes, so the smallest is the oldest
+        while generations.len() > MAX_GENERATIONS {
+            if let Some(oldest) = generations.keys().min().copied() {
+                generations.remove(&oldest);
+            }
+        }
+    }
+
+    fn finish_generation(&self, key: u64, my_messages: &[Message]) {
+        if let Some(generation) = self
+            .generations
+            .lock()
+            .expect("generations lock poisoned")
+            .get_mut(&key)
+        {
+            generation.stop = None;
+            generation.extra_messages = my_messages
+                .iter()
+                .skip(1)
+                .map(|my_message| (my_message.channel_id, my_message.id))
+                .collect();
+        }
+    }
+
+    pub async fn on_component(
+        &self,
+        ctx: &serenity::Context,
+        interaction: &ComponentInteraction,
+    ) -> Result<(), Error> {
+        let Some((action, key)) = interaction
+            .data
+            .custom_id
+            .strip_prefix(BUTTON_PREFIX)
+            .and_then(|custom_id| custom_id.split_once(':'))
+        else {
+            return Ok(());
+        };
+        let key: u64 = key.parse()?;
+
+        let generation = self
+            .generations
+            .lock()
+            .expect("generations lock poisoned")
+            .get(&key)
+            .cloned();
+        let Some(generation) = generation else {
+            return self
+                .respond_ephemeral(ctx, interaction, "This reply can no longer be changed.")
+                .await;
+        };
+
+        if interaction.user.id != generation.origin.user_id {
+            return self
+                .respond_ephemeral(ctx, interaction, "Only the asking user can do this.")
+                .await;
+        }
+
+        interaction
+            .create_response(ctx, CreateInteractionResponse::Acknowledge)
+            .await?;
+
+        match action {
+            "stop" => {
+                if let Some(stop) = &generation.stop {
+                    // the stream is dropped, which aborts the request
+                    let _ = stop.send(true);
+                }
+            }
+            "regenerate" => {
+                if generation.stop.is_some() {
+                    interaction
+                        .create_followup(
+                            ctx,
+                            CreateInteractionResponseFollowup::new()
+                                .content("The reply is still being generated.")
+                                .ephemeral(true),
+                        )
+                        .await?;
+                    return Ok(());
+                }
+                if let Some(refusal) = self.check_quota(&generation.origin).await? {
+                    interaction
+                        .create_followup(
+                            ctx,
+                            CreateInteractionResponseFollowup::new()
+                                .content(refusal)
+                                .ephemeral(true),
+                        )
                         .await?;
-                    count += reply_buffer.chars().count();
+                    return Ok(());
                 }
 
-                reply_buffer.clear();
+                // ephemeral followups can't be deleted through the channel
+                if !generation.ephemeral {
+                    for (channel_id, message_id) in &generation.extra_messages {
+                        channel_id.delete_message(ctx, *message_id).await?;
+                    }
+                }
+
+                let mut output = Output::component(interaction, key, generation.ephemeral);
+                let reply = self
+                    .complete(
+                        ctx,
+                        &generation.origin,
+                        &generation.model,
+                        generation.messages,
+                        &mut output,
+                    )
+                    .await?;
+
+                let channel_id = generation.origin.channel_id;
+                if self.is_thread(channel_id) {
+                    self.db
+                        .update_thread_reply(channel_id.get(), key, &reply)
+                        .await?;
+                }
             }
+            _ => {}
         }
 
         Ok(())
     }
 
-    async fn get_reply_chain(
+    async fn respond_ephemeral(
         &self,
         ctx: &serenity::Context,
-        message: &serenity::Message,
-        chain: &mut Vec<serenity::Message>,
+        interaction: &ComponentInteraction,
+        content: &str,
     ) -> Result<(), Error> {
-        if let Some(referenced_message) = &message.referenced_message {
-            let message = ctx
-                .http()
-                .get_message(referenced_message.channel_id, referenced_message.id)
-                .await?;
+        interaction
+            .create_response(
+                ctx,
+                CreateInteractionResponse::Message(
+                    serenity::CreateInteractionResponseMessage::new()
+                        .content(content)
+                        .ephemeral(true),
+                ),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Streams a completion into the reply and returns the tool calls the
+    /// model requested, if any. The usage is estimated if the provider does
+    /// not report it.
+    async fn stream_reply(
+        &self,
+        ctx: &serenity::Context,
+        request: &CompletionRequest<'_>,
+        reply: &mut String,
+        usage: &mut Option<Usage>,
+        stop: &mut watch::Receiver<bool>,
+        output: &mut Output<'_>,
+    ) -> Result<Vec<ToolCall>, Error> {
+        let mut stream = self.provider.stream(request).await?;
+
+        let start = reply.len();
+        let mut reply_buffer = String::new();
+        let mut tool_calls: Vec<ToolCall> = vec![];
+        let mut stopped = false;
+        let mut last_flush = Instant::now();
+
+        loop {
+            // None when it is time to flush, even if the stream has stalled
+            let next = tokio::select! {
+                event = stream.next() => Some(event),
+                Ok(_) = stop.wait_for(|stopped| *stopped) => {
+                    stopped = true;
+                    Some(None)
+                }
+                _ = tokio::time::sleep_until(last_flush + FLUSH_INTERVAL),
+                    if !reply_buffer.is_empty() => None,
+            };
+            let mut done = false;
+
+            match next {
+                Some(Some(event)) => match event? {
+                    StreamEvent::Text(text) => reply_buffer.push_str(&text),
+                    StreamEvent::ToolCall(tool_call) => tool_calls.push(tool_call),
+                    StreamEvent::Finish(_) => done = true,
+                    StreamEvent::Usage(reported) => *usage = Some(reported),
+                },
+                Some(None) => break,
+                None => {}
+            }
+
+            if !reply_buffer.is_empty()
+                && (done || flush_due(last_flush.elapsed(), reply_buffer.chars().count()))
+            {
+                reply.push_str(&reply_buffer);
+                output.render(ctx, reply, false).await?;
+
+                reply_buffer.clear();
+                last_flush = Instant::now();
+            }
+        }
+
+        // the rest is rendered when the reply is finished, even if the stream
+        // ended without a finish reason
+        reply.push_str(&reply_buffer);
+
+        if usage.is_none() {
+            let completion = ChatMessage {
+                content: vec![Part::Text(reply[start..].to_owned())],
+                ..ChatMessage::tool_calls(tool_calls.clone())
+            };
+            *usage = Some(Usage::estimate(request.messages, &completion));
+        }
+
+        if stopped {
+            return Ok(vec![]);
+        }
+
+        Ok(tool_calls)
+    }
+
+    async fn record_usage(&self, origin: &Origin, model: &str, usage: Usage) {
+        if let Err(e) = self
+            .db
+            .add_usage(
+                origin.user_id.get(),
+                origin.guild_id.map(|guild_id| guild_id.get()),
+                model,
+                usage.prompt_tokens,
+                usage.completion_tokens,
+            )
+            .await
+        {
+            log::warn!("Failed to record usage: {e}");
+        }
+    }
+
+    /// Tokens used since the time per model
+    async fn get_usage(
+        &self,
+        scope: &str,
+        id: u64,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Vec<(String, Usage)>, Error> {
+        self.db
+            .get_usage(scope, id, since)
+            .await?
+            .iter()
+            .map(|usage| {
+                Ok((
+                    usage.get_str("_id")?.to_owned(),
+                    Usage {
+                        prompt_tokens: usage.get_i64("prompt_tokens")? as u64,
+                        completion_tokens: usage.get_i64("completion_tokens")? as u64,
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    /// Returns a refusal if the user or the server has used up the daily quota
+    async fn check_quota(&self, origin: &Origin) -> Result<Option<String>, Error> {
+        let since = start_of_today();
+
+        if let Some(limit) = self.user_daily_tokens {
+            let used = total_tokens(
+                &self
+                    .get_usage("user_id", origin.user_id.get(), since)
+                    .await?,
+            );
+            if used >= limit {
+                return Ok(Some(format!(
+                    "You have used up today's quota ({used} / {limit} tokens). It resets at midnight JST."
+                )));
+            }
+        }
+
+        if let (Some(limit), Some(guild_id)) = (self.guild_daily_tokens, origin.guild_id) {
+            let used = total_tokens(&self.get_usage("guild_id", guild_id.get(), since).await?);
+            if used >= limit {
+                return Ok(Some(format!(
+                    "This server has used up today's quota ({used} / {limit} tokens). It resets at midnight JST."
+                )));
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub async fn usage(&self, ctx: Context<'_>, server: Option<bool>) -> Result<(), Error> {
+        let (scope, id, limit) = if server.unwrap_or(false) {
+            let guild_id = ctx.guild_id().ok_or("Not in a server")?;
+            ("guild_id", guild_id.get(), self.guild_daily_tokens)
+        } else {
+            ("user_id", ctx.author().id.get(), self.user_daily_tokens)
+        };
+
+        let today = self.get_usage(scope, id, start_of_today()).await?;
+        let month = self
+            .get_usage(scope, id, chrono::Utc::now() - chrono::Duration::days(30))
+            .await?;
 
-            Box::pin(self.get_reply_chain(ctx, &message, chain)).await?;
+        let mut reply = format!(
+            "Today:\n{}\nLast 30 days:\n{}",
+            describe_usage(&today),
+            describe_usage(&month)
+        );
+        if let Some(limit) = limit {
+            reply.push_str(&format!(
+                "\nDaily quota: {} / {limit} tokens",
+                total_tokens(&today)
+            ));
         }
 
-        chain.push(message.clone());
+        ctx.send(CreateReply::default().content(reply).ephemeral(true))
+            .await?;
 
         Ok(())
     }
 
-    fn delete_mention_to_myself(&self, message: &serenity::Message) -> String {
-        self.mention_pattern
-            .replace_all(&message.content, "")
-            .to_string()
-    }
-
-    async fn get_image_base64(&self, attachment: &Attachment) -> Result<String, Error> {
-        let content = attachment.download().await?;
-        let base64_content = BASE64_STANDARD.encode(content);
-        Ok(format!(
-            "data:{};base64,{}",
-            attachment.content_type.as_deref().unwrap_or("image/png"),
-            base64_content
-        ))
+    /// Attachments that could not be read are left out and returned as errors
+    async fn to_chat_message(&self, m: &serenity::Message) -> (ChatMessage, Vec<Error>) {
+        if m.author.id == self.bot.id {
+            return (
+                ChatMessage::text(Role::Assistant, m.content.clone()),
+                vec![],
+            );
+        }
+
+        let images = futures::future::join_all(
+            m.attachments
+                .iter()
+                .filter(|a| is_image(a))
+                .map(|a| self.get_image_base64(a)),
+        )
+        .await;
+
+        let documents = futures::future::join_all(
+            m.attachments
+                .iter()
+                .filter(|a| !is_image(a) && is_document(a))
+                .map(extract_text),
+        )
+        .await;
+
+        let mut content = vec![Part::Text(self.delete_mention_to_myself(m))];
+        let mut errors = vec![];
+        for document in documents {
+            match document {
+                Ok(document) => content.push(Part::Text(document)),
+                Err(e) => {
+                    log::warn!("Failed to read an attachment: {e}");
+                    errors.push(e);
+                }
+            }
+        }
+        for image in images {
+            match image {
+                Ok(image) => content.push(image),
+                Err(e) => {
+                    log::warn!("Failed to read an image: {e}");
+                    errors.push(e);
+                }
+            }
+        }
+
+        (ChatMessage::new(Role::User, content), errors)
+    }
+
+    /// The channel prompt takes precedence over the guild prompt.
+    /// `{server}`, `{now}` and `{user}` are replaced with the server name,
+    /// the current time in JST and the display name of the asking user.
+    async fn get_system_prompt(
+        &self,
+        ctx: &serenity::Context,
+        origin: &Origin,
+    ) -> Result<String, Error> {
+        let prompt = match origin.guild_id {
+            Some(guild_id) => {
+                match self
+                    .db
+                    .get_system_prompt(guild_id.get(), Some(origin.channel_id.get()))
+                    .await?
+                {
+                    Some(prompt) => Some(prompt),
+                    None => self.db.get_system_prompt(guild_id.get(), None).await?,
+                }
+            }
+            None => None,
+        };
+        let prompt = prompt.unwrap_or(DEFAULT_SYSTEM_PROMPT.to_owned());
+
+        let server = origin
+            .guild_id
+            .and_then(|guild_id| ctx.cache.guild(guild_id).map(|guild| guild.name.clone()))
+            .unwrap_or_default();
+        let now = chrono::Utc::now()
+            .with_timezone(&Tokyo)
+            .format("%Y-%m-%d %H:%M")
+            .to_string();
+        let mut prompt = prompt
+            .replace("{server}", &server)
+            .replace("{now}", &now)
+            .replace("{user}", &origin.user_name);
+
+        let memories = self.get_memories(origin.user_id).await?;
+        if !memories.is_empty() {
+            prompt.push_str("\n\nWhat you remember about the user:");
+            for (_, memory) in memories {
+                prompt.push_str(&format!("\n- {memory}"));
+            }
+        }
+
+        Ok(prompt)
+    }
+
+    /// Past messages of the server relevant to the question, as a system
+    /// message. Retrieval failures only leave them out of the context.
+    /// Public replies only use messages everyone in the channel can read.
+    async fn get_knowledge(
+        &self,
+        ctx: &serenity::Context,
+        origin: &Origin,
+        query: &str,
+        exclude: Option<MessageId>,
+        public: bool,
+    ) -> Option<ChatMessage> {
+        let knowledge = self.knowledge.as_ref()?;
+        let guild_id = origin.guild_id?;
+        if query.trim().is_empty() {
+            return None;
+        }
+
+        let snippets = knowledge
+            .retrieve(
+                ctx,
+                guild_id,
+                origin.user_id,
+                public.then_some(origin.channel_id),
+                query,
+                exclude,
+            )
+            .await
+            .inspect_err(|e| log::warn!("Failed to retrieve past messages: {e}"))
+            .ok()?;
+        if snippets.is_empty() {
+            return None;
+        }
+
+        let mut text = String::from(KNOWLEDGE_PROMPT);
+        for snippet in snippets {
+            text.push_str(&format!(
+                "\n- [{} {}](<{}>): {}",
+                snippet.author, snippet.date, snippet.link, snippet.content
+            ));
+        }
+
+        Some(ChatMessage::text(Role::System, text))
+    }
+
+    /// The ids and contents of the memories of the user, oldest first
+    async fn get_memories(&self, user_id: UserId) -> Result<Vec<(ObjectId, String)>, Error> {
+        parse_memories(&self.db.get_memories(user_id.get()).await?)
+    }
+
+    pub async fn list_memories(&self, ctx: Context<'_>) -> Result<(), Error> {
+        let memories = self.get_memories(ctx.author().id).await?;
+        let reply = format_memories(&memories);
+
+        for piece in split_message(&reply, MAX_MESSAGE_LENGTH) {
+            ctx.send(CreateReply::default().content(piece).ephemeral(true))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// `number` is the one shown by /memory list
+    pub async fn forget_memory(&self, ctx: Context<'_>, number: usize) -> Result<(), Error> {
+        let user_id = ctx.author().id;
+        let memories = self.get_memories(user_id).await?;
+
+        let reply = match memory_at(&memories, number) {
+            Some((id, memory)) => {
+                self.db.remove_memory(user_id.get(), *id).await?;
+                format!("Forgot: {memory}")
+            }
+            None => format!("There is no memory {number}. See /memory list."),
+        };
+
+        ctx.send(CreateReply::default().content(reply).ephemeral(true))
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn forget_all_memories(&self, ctx: Context<'_>) -> Result<(), Error> {
+        let count = self.db.remove_memories(ctx.author().id.get()).await?;
+
+        ctx.send(
+            CreateReply::default()
+                .content(format!("Forgot {count} memories."))
+                .ephemeral(true),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn set_system_prompt(
+        &self,
+        ctx: Context<'_>,
+        prompt: String,
+        channel: Option<bool>,
+    ) -> Result<(), Error> {
+        let guild_id = ctx.guild_id().expect("failed to get guild id");
+        let channel_id = channel.unwrap_or(false).then_some(ctx.channel_id().get());
+
+        self.db
+            .update_system_prompt(guild_id.get(), channel_id, prompt)
+            .await?;
+
+        ctx.reply("The system prompt has been updated.").await?;
+
+        Ok(())
+    }
+
+    pub async fn reset_system_prompt(
+        &self,
+        ctx: Context<'_>,
+        channel: Option<bool>,
+    ) -> Result<(), Error> {
+        let guild_id = ctx.guild_id().expect("failed to get guild id");
+        let channel_id = channel.unwrap_or(false).then_some(ctx.channel_id().get());
+
+        if self
+            .db
+            .remove_system_prompt(guild_id.get(), channel_id)
+            .await?
+        {
+            ctx.reply("The system prompt has been reset.").await?;
+        } else {
+            ctx.reply("No system prompt is set.").await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn show_system_prompt(&self, ctx: Context<'_>) -> Result<(), Error> {
+        let guild_id = ctx.guild_id().expect("failed to get guild id").get();
+        let channel_id = ctx.channel_id().get();
+
+        let reply = match self
+            .db
+            .get_system_prompt(guild_id, Some(channel_id))
+            .await?
+        {
+            Some(prompt) => format!("Channel prompt:\n{prompt}"),
+            None => match self.db.get_system_prompt(guild_id, None).await? {
+                Some(prompt) => format!("Server prompt:\n{prompt}"),
+                None => format!("Default prompt:\n{DEFAULT_SYSTEM_PROMPT}"),
+            },
+        };
+
+        ctx.send(CreateReply::default().content(reply).ephemeral(true))
+            .await?;
+
+        Ok(())
+    }
+
+    /// The message and the ones it replies to, oldest first
+    async fn get_reply_chain(
+        &self,
+        ctx: &serenity::Context,
+        message: &serenity::Message,
+    ) -> Result<Vec<serenity::Message>, Error> {
+        // older messages would be trimmed from the context anyway, unless
+        // they are summarized
+        let budget = if self.summarize {
+            self.context_tokens * 2
+        } else {
+            self.context_tokens
+        };
+        let mut tokens = estimate_message_tokens(message);
+        let mut chain = vec![message.clone()];
+
+        while let Some(referenced_message) = chain
+            .last()
+            .and_then(|message| message.referenced_message.as_ref())
+            .filter(|_| chain.len() < MAX_REPLY_CHAIN && tokens < budget)
+        {
+            let message = ctx
+                .http()
+                .get_message(referenced_message.channel_id, referenced_message.id)
+                .await?;
+            tokens += estimate_message_tokens(&message);
+            chain.push(message);
+        }
+
+        chain.reverse();
+
+        Ok(chain)
+    }
+
+    /// The messages of the channel before the reply chain, labelled with the
+    /// names of their authors. Replies of the bot stay its own turns, so that
+    /// the model does not answer them as questions.
+    async fn get_channel_history(
+        &self,
+        ctx: &serenity::Context,
+        chain: &[serenity::Message],
+        count: u8,
+    ) -> Result<Vec<ChatMessage>, Error> {
+        let Some(first) = chain.first() else {
+            return Ok(vec![]);
+        };
+        let history = first
+            .channel_id
+            .messages(ctx, GetMessages::new().before(first.id).limit(count))
+            .await?;
+
+        // newest first
+        Ok(history
+            .iter()
+            .rev()
+            .filter(|m| !m.content.trim().is_empty())
+            .map(|m| {
+                if m.author.id == self.bot.id {
+                    ChatMessage::text(Role::Assistant, m.content.clone())
+                } else {
+                    ChatMessage::text(
+                        Role::User,
+                        format!(
+                            "{}: {}",
+                            m.author.display_name(),
+                            self.delete_mention_to_myself(m)
+                        ),
+                    )
+                }
+            })
+            .collect())
+    }
+
+    /// Also removes the prefix of one-off options
+    fn delete_mention_to_myself(&self, message: &serenity::Message) -> String {
+        let content = self.mention_pattern.replace_all(&message.content, "");
+        parse_options(&self.option_pattern, &content).1.to_owned()
+    }
+
+    fn get_options<'a>(&self, message: &'a serenity::Message) -> Options<'a> {
+        let content = message.content.trim_start();
+        let content = match self.mention_pattern.find(content) {
+            Some(mention) if mention.start() == 0 => &content[mention.end()..],
+            _ => content,
+        };
+
+        parse_options(&self.option_pattern, content).0
+    }
+
+    /// The user preference takes precedence over the channel preference,
+    /// which takes precedence over the server preference
+    async fn get_preferred_model(&self, origin: &Origin) -> Result<String, Error> {
+        let mut scopes = vec![
+            ("user", origin.user_id.get()),
+            ("channel", origin.channel_id.get()),
+        ];
+        if let Some(guild_id) = origin.guild_id {
+            scopes.push(("guild", guild_id.get()));
+        }
+
+        for (scope, id) in scopes {
+            // models removed from LLM_MODELS are ignored
+            if let Some(model) = self.db.get_model(scope, id).await? {
+                if let Some(model) = self.models.resolve(&model) {
+                    return Ok(model.to_owned());
+                }
+            }
+        }
+
+        Ok(self.models.default_model().to_owned())
+    }
+
+    pub async fn model(
+        &self,
+        ctx: Context<'_>,
+        name: Option<String>,
+        scope: Option<ModelScope>,
+    ) -> Result<(), Error> {
+        let guild_id = ctx.guild_id().map(|guild_id| guild_id.get());
+
+        let Some(name) = name else {
+            let model = self
+                .get_preferred_model(&Origin::from_command(ctx).await)
+                .await?;
+            ctx.send(
+                CreateReply::default()
+                    .content(format!(
+                        "Current model: {model}\nAvailable models:\n{}",
+                        self.models.describe()
+                    ))
+                    .ephemeral(true),
+            )
+            .await?;
+
+            return Ok(());
+        };
+
+        let (scope, id) = match scope.unwrap_or(ModelScope::User) {
+            ModelScope::User => ("user", ctx.author().id.get()),
+            ModelScope::Channel | ModelScope::Server if !self.can_manage_guild(ctx).await => {
+                ctx.reply("You need the Manage Server permission to change this setting.")
+                    .await?;
+                return Ok(());
+            }
+            ModelScope::Channel => ("channel", ctx.channel_id().get()),
+            ModelScope::Server => ("guild", guild_id.ok_or("Not in a server")?),
+        };
+
+        if name == "default" {
+            self.db.remove_model(scope, id).await?;
+            ctx.reply("The model has been reset.").await?;
+
+            return Ok(());
+        }
+
+        match self.models.resolve(&name) {
+            Some(model) => {
+                self.db.update_model(scope, id, model).await?;
+                ctx.reply(format!("The model has been set to {model}."))
+                    .await?;
+            }
+            None => {
+                ctx.reply(format!("Unknown model {name}.\n{}", self.models.describe()))
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn ask(
+        &self,
+        ctx: Context<'_>,
+        prompt: String,
+        attachment: Option<Attachment>,
+        model: Option<String>,
+        ephemeral: bool,
+    ) -> Result<(), Error> {
+        let origin = Origin::from_command(ctx).await;
+
+        let model = match model {
+            Some(name) => match self.models.resolve(&name) {
+                Some(model) => model.to_owned(),
+                None => {
+                    ctx.send(
+                        CreateReply::default()
+                            .content(format!("Unknown model {name}.\n{}", self.models.describe()))
+                            .ephemeral(true),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+            },
+            None => self.get_preferred_model(&origin).await?,
+        };
+
+        if let Some(refusal) = self.check_quota(&origin).await? {
+            ctx.send(CreateReply::default().content(refusal).ephemeral(true))
+                .await?;
+            return Ok(());
+        }
+
+        if ephemeral {
+            ctx.defer_ephemeral().await?;
+        } else {
+            ctx.defer().await?;
+        }
+
+        // the deferred response has to be resolved even when this fails
+        let messages = match self
+            .get_ask_messages(ctx, &origin, prompt, attachment, ephemeral)
+            .await
+        {
+            Ok(messages) => messages,
+            Err(e) => {
+                ctx.send(
+                    CreateReply::default()
+                        .content(describe_error(&e))
+                        .ephemeral(ephemeral),
+                )
+                .await?;
+                return Err(e);
+            }
+        };
+        let mut output = Output::command(ctx, ephemeral);
+
+        self.complete(
+            ctx.serenity_context(),
+            &origin,
+            &model,
+            messages,
+            &mut output,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_ask_messages(
+        &self,
+        ctx: Context<'_>,
+        origin: &Origin,
+        prompt: String,
+        attachment: Option<Attachment>,
+        ephemeral: bool,
+    ) -> Result<Vec<ChatMessage>, Error> {
+        let knowledge = self
+            .get_knowledge(ctx.serenity_context(), origin, &prompt, None, !ephemeral)
+            .await;
+
+        let mut content = vec![Part::Text(prompt)];
+        match attachment {
+            Some(image) if is_image(&image) => content.push(self.get_image_base64(&image).await?),
+            Some(document) if is_document(&document) => {
+                content.push(Part::Text(extract_text(&document).await?))
+            }
+            _ => {}
+        }
+
+        let mut messages = vec![ChatMessage::text(
+            Role::System,
+            self.get_system_prompt(ctx.serenity_context(), origin)
+                .await?,
+        )];
+        messages.extend(knowledge);
+        messages.push(ChatMessage::new(Role::User, content));
+
+        Ok(messages)
+    }
+
+    pub async fn thread_chat(&self, ctx: Context<'_>, name: Option<String>) -> Result<(), Error> {
+        let guild_id = ctx.guild_id().expect("failed to get guild id");
+        let origin = Origin::from_command(ctx).await;
+        let name = name.unwrap_or(format!("Chat with {}", origin.user_name));
+
+        let thread = ctx
+            .channel_id()
+            .create_thread(
+                ctx,
+                CreateThread::new(name).kind(serenity::ChannelType::PublicThread),
+            )
+            .await?;
+
+        self.db
+            .add_thread(thread.id.get(), guild_id.get(), origin.user_id.get())
+            .await?;
+        self.threads
+            .lock()
+            .expect("threads lock poisoned")
+            .insert(thread.id);
+
+        ctx.reply(format!(
+            "Started a conversation in {}. Every message there is sent to the LLM.",
+            thread.mention()
+        ))
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn summarize(
+        &self,
+        ctx: Context<'_>,
+        hours: Option<u32>,
+        ephemeral: bool,
+    ) -> Result<(), Error> {
+        let origin = Origin::from_command(ctx).await;
+
+        if let Some(refusal) = self.check_quota(&origin).await? {
+            ctx.send(CreateReply::default().content(refusal).ephemeral(true))
+                .await?;
+            return Ok(());
+        }
+
+        if ephemeral {
+            ctx.defer_ephemeral().await?;
+        } else {
+            ctx.defer().await?;
+        }
+
+        let since = hours.map(|hours| chrono::Utc::now() - chrono::Duration::hours(hours.into()));
+        let messages = match self.get_recent_messages(ctx, since).await {
+            Ok(messages) => messages,
+            Err(e) => {
+                ctx.send(
+                    CreateReply::default()
+                        .content(describe_error(&e))
+                        .ephemeral(ephemeral),
+                )
+                .await?;
+                return Err(e);
+            }
+        };
+        let (transcript, messages) = self.to_transcript(ctx, &messages);
+        if messages.is_empty() {
+            ctx.send(
+                CreateReply::default()
+                    .content("There are no messages to summarize.")
+                    .ephemeral(ephemeral),
+            )
+            .await?;
+            return Ok(());
+        }
+
+        let model = self.get_preferred_model(&origin).await?;
+        let summary = complete_text(
+            self.provider.as_ref(),
+            &CompletionRequest {
+                model: &model,
+                messages: &[
+                    ChatMessage::text(Role::System, CHANNEL_SUMMARY_PROMPT),
+                    ChatMessage::text(Role::User, transcript),
+                ],
+                tools: &[],
+            },
+        )
+        .await;
+        let summary = match summary {
+            Ok((summary, usage)) => {
+                self.record_usage(&origin, &model, usage).await;
+                summary
+            }
+            Err(e) => {
+                ctx.send(
+                    CreateReply::default()
+                        .content(describe_error(&e))
+                        .ephemeral(ephemeral),
+                )
+                .await?;
+                return Err(e);
+            }
+        };
+
+        let links = messages.iter().map(Message::link).collect::<Vec<_>>();
+        let summary = link_citations(&self.citation_pattern, &summary, &links);
+        for piece in split_message(&summary, MAX_MESSAGE_LENGTH) {
+            ctx.send(CreateReply::default().content(piece).ephemeral(ephemeral))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Messages of the channel newer than `since`, or after the last message
+    /// of the user when `since` is None, oldest first
+    async fn get_recent_messages(
+        &self,
+        ctx: Context<'_>,
+        since: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<Vec<Message>, Error> {
+        let mut messages = vec![];
+        let mut before = None;
+
+        while messages.len() < MAX_SUMMARY_MESSAGES {
+            let mut request = GetMessages::new().limit(MAX_HISTORY_MESSAGES);
+            if let Some(before) = before {
+                request = request.before(before);
+            }
+            // newest first
+            let page = ctx.channel_id().messages(ctx, request).await?;
+            let Some(last) = page.last() else {
+                break;
+            };
+            before = Some(last.id);
+            let is_last_page = page.len() < MAX_HISTORY_MESSAGES as usize;
+
+            if take_recent(page, since, ctx.author().id, &mut messages) || is_last_page {
+                break;
+            }
+        }
+
+        messages.reverse();
+
+        Ok(messages)
+    }
+
+    /// Numbers the most recent messages that fit in the context, returning
+    /// the transcript and the numbered messages
+    fn to_transcript<'a>(
+        &self,
+        ctx: Context<'_>,
+        messages: &'a [Message],
+    ) -> (String, &'a [Message]) {
+        let lines = messages
+            .iter()
+            .map(|m| {
+                let mut content = m.content_safe(ctx.serenity_context());
+                for attachment in &m.attachments {
+                    content.push_str(&format!(" (attachment: {})", attachment.filename));
+                }
+                format!(
+                    "{} {}: {}",
+                    m.timestamp.with_timezone(&Tokyo).format("%m-%d %H:%M"),
+                    m.author.display_name(),
+                    content
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let start = transcript_start(&lines, self.context_tokens.saturating_sub(SUMMARY_TOKENS));
+        let transcript = lines[start..]
+            .iter()
+            .enumerate()
+            .map(|(i, line)| format!("[{}] {line}", i + 1))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        (transcript, &messages[start..])
+    }
+
+    pub async fn translate(
+        &self,
+        ctx: Context<'_>,
+        text: String,
+        language: Option<String>,
+        ephemeral: bool,
+    ) -> Result<(), Error> {
+        let origin = Origin::from_command(ctx).await;
+
+        if let Some(refusal) = self.check_quota(&origin).await? {
+            ctx.send(CreateReply::default().content(refusal).ephemeral(true))
+                .await?;
+            return Ok(());
+        }
+
+        if ephemeral {
+            ctx.defer_ephemeral().await?;
+        } else {
+            ctx.defer().await?;
+        }
+
+        let translation = match self
+            .get_translation(&origin, &text, language.as_deref())
+            .await
+        {
+            Ok(translation) => translation,
+            Err(e) => {
+                ctx.send(
+                    CreateReply::default()
+                        .content(describe_error(&e))
+                        .ephemeral(ephemeral),
+                )
+                .await?;
+                return Err(e);
+            }
+        };
+
+        for piece in split_message(&translation, MAX_MESSAGE_LENGTH) {
+            ctx.send(CreateReply::default().content(piece).ephemeral(ephemeral))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Replies with a translation when a message gets a flag reaction, once
+    /// per message and language
+    pub async fn on_reaction(
+        &self,
+        ctx: &serenity::Context,
+        reaction: &Reaction,
+    ) -> Result<(), Error> {
+        let ReactionType::Unicode(emoji) = &reaction.emoji else {
+            return Ok(());
+        };
+        let Some(language) = flag_language(emoji) else {
+            return Ok(());
+        };
+        let Some(user_id) = reaction.user_id.filter(|user_id| *user_id != self.bot.id) else {
+            return Ok(());
+        };
+
+        if !self.translations.claim(reaction.message_id, language) {
+            return Ok(());
+        }
+
+        let result = self
+            .translate_reaction(ctx, reaction, user_id, language)
+            .await;
+        if result.is_err() {
+            self.translations.release(reaction.message_id, language);
+        }
+
+        result
+    }
+
+    async fn translate_reaction(
+        &self,
+        ctx: &serenity::Context,
+        reaction: &Reaction,
+        user_id: UserId,
+        language: &'static str,
+    ) -> Result<(), Error> {
+        let message = reaction.message(ctx).await?;
+        if message.content.trim().is_empty() {
+            return Ok(());
+        }
+
+        let origin = Origin {
+            user_id,
+            user_name: reaction
+                .member
+                .as_ref()
+                .map(|member| member.display_name().to_owned())
+                .unwrap_or_default(),
+            channel_id: reaction.channel_id,
+            guild_id: reaction.guild_id,
+        };
+
+        if let Some(refusal) = self.check_quota(&origin).await? {
+            log::info!("Translation for {user_id} refused: {refusal}");
+            self.translations.release(message.id, language);
+            return Ok(());
+        }
+
+        let typing = message.channel_id.start_typing(&ctx.http);
+        let translation = self
+            .get_translation(&origin, &message.content_safe(ctx), Some(language))
+            .await;
+        drop(typing);
+
+        let translation = match translation {
+            Ok(translation) => translation,
+            Err(e) => {
+                message.reply(ctx, describe_error(&e)).await?;
+                return Err(e);
+            }
+        };
+
+        for (i, piece) in split_message(&translation, MAX_MESSAGE_LENGTH)
+            .into_iter()
+            .enumerate()
+        {
+            if i == 0 {
+                message.reply(ctx, piece).await?;
+            } else {
+                message.channel_id.say(ctx, piece).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Translates into `language`, or between Japanese and English when None
+    async fn get_translation(
+        &self,
+        origin: &Origin,
+        text: &str,
+        language: Option<&str>,
+    ) -> Result<String, Error> {
+        let model = self.get_preferred_model(origin).await?;
+        let instruction = match language {
+            Some(language) => format!("Translate the following text into {language}."),
+            None => DEFAULT_TRANSLATION.to_owned(),
+        };
+
+        let (translation, usage) = complete_text(
+            self.provider.as_ref(),
+            &CompletionRequest {
+                model: &model,
+                messages: &[
+                    ChatMessage::text(Role::System, format!("{instruction} {TRANSLATION_PROMPT}")),
+                    ChatMessage::text(Role::User, text),
+                ],
+                tools: &[],
+            },
+        )
+        .await?;
+        self.record_usage(origin, &model, usage).await;
+
+        Ok(translation)
+    }
+
+    pub async fn channel_history(
+        &self,
+        ctx: Context<'_>,
+        messages: Option<u8>,
+    ) -> Result<(), Error> {
+        let channel_id = ctx.channel_id().get();
+
+        match messages {
+            None => match self.db.get_channel_history(channel_id).await? {
+                Some(messages) => {
+                    ctx.reply(format!(
+                        "The last {messages} messages of this channel are included."
+                    ))
+                    .await?
+                }
+                None => ctx.reply("Channel history is not included.").await?,
+            },
+            Some(0) => {
+                self.db.remove_channel_history(channel_id).await?;
+                ctx.reply("Channel history will no longer be included.")
+                    .await?
+            }
+            Some(messages) => {
+                self.db.update_channel_history(channel_id, messages).await?;
+                ctx.reply(format!(
+                    "The last {messages} messages of this channel will be included."
+                ))
+                .await?
+            }
+        };
+
+        Ok(())
+    }
+
+    async fn can_manage_guild(&self, ctx: Context<'_>) -> bool {
+        match ctx.author_member().await {
+            Some(member) => member
+                .permissions
+                .is_some_and(|permissions| permissions.manage_guild()),
+            None => false,
+        }
+    }
+
+    async fn get_image_base64(&self, attachment: &Attachment) -> Result<Part, Error> {
+        let image =
+            image::download(&self.http_client, attachment, self.image_max_dimension).await?;
+        Ok(Part::Image {
+            media_type: image.media_type,
+            data: BASE64_STANDARD.encode(image.data),
+        })
+    }
+}
+
+fn describe_error(e: &Error) -> String {
+    if let Some(e) = e.downcast_ref::<DocumentError>() {
+        return e.to_string();
+    }
+    if let Some(e) = e.downcast_ref::<ImageError>() {
+        return e.to_string();
+    }
+    if let Some(e) = e.downcast_ref::<ProviderError>() {
+        return match e {
+            ProviderError::Http {
+                status: 429,
+                retry_after,
+                ..
+            } => format!("The LLM API is rate limited. {}", retry_hint(*retry_after)),
+            ProviderError::Http {
+                status: 401 | 403, ..
+            } => String::from("The LLM API rejected the credentials. Please tell the bot admin."),
+            ProviderError::Http { status: 404, .. } => {
+                String::from("The model was not found. Please choose another one with /model.")
+            }
+            ProviderError::Http {
+                status,
+                retry_after,
+                ..
+            } if *status >= 500 => format!(
+                "The LLM API is unavailable (HTTP {status}). {}",
+                retry_hint(*retry_after)
+            ),
+            ProviderError::Http {
+                status, message, ..
+            } => format!(
+                "The LLM API returned HTTP {status}: {}",
+                truncate(message, MAX_ERROR_LENGTH)
+            ),
+            ProviderError::Empty => {
+                String::from("The model returned an empty response. Please regenerate it.")
+            }
+        };
+    }
+    if let Some(e) = e.downcast_ref::<CompletionError>() {
+        return match e {
+            CompletionError::Api(message)
+                if ["rate limit", "overloaded"]
+                    .iter()
+                    .any(|pattern| message.to_lowercase().contains(pattern)) =>
+            {
+                format!("The LLM API is busy. {}", retry_hint(None))
+            }
+            CompletionError::Api(message) => format!(
+                "The LLM API returned an error: {}",
+                truncate(message, MAX_ERROR_LENGTH)
+            ),
+            CompletionError::Malformed(_) => {
+                String::from("The LLM API sent a response that could not be read.")
+            }
+        };
+    }
+
+    if e.is::<tokio::time::error::Elapsed>() {
+        return String::from("The reply took too long and was stopped. Please regenerate it.");
+    }
+
+    if let Some(e) = e.downcast_ref::<reqwest::Error>() {
+        if e.is_timeout() {
+            return String::from("The LLM API did not respond in time. Please try again.");
+        }
+        if e.is_connect() {
+            return String::from("Could not connect to the LLM API.");
+        }
+    }
+
+    String::from("Something went wrong while generating the reply.")
+}
+
+fn retry_hint(retry_after: Option<u64>) -> String {
+    match retry_after {
+        Some(seconds) => format!("Please try again in {seconds} seconds."),
+        None => String::from("Please try again in a moment."),
+    }
+}
+
+/// The ids and contents of stored memories
+fn parse_memories(memories: &[Document]) -> Result<Vec<(ObjectId, String)>, Error> {
+    memories
+        .iter()
+        .map(|memory| {
+            Ok((
+                memory.get_object_id("_id")?,
+                memory.get_str("content")?.to_owned(),
+            ))
+        })
+        .collect()
+}
+
+/// Splits the options from the start of the content
+fn parse_options<'a>(pattern: &Regex, mut content: &'a str) -> (Options<'a>, &'a str) {
+    let mut options = Options::default();
+
+    while let Some(captures) = pattern.captures(content) {
+        if let Some(model) = captures.get(1) {
+            options.model = Some(model.as_str());
+        }
+        if let Some(history) = captures.get(2) {
+            options.history = Some(
+                history
+                    .as_str()
+                    .parse::<usize>()
+                    .map_or(DEFAULT_HISTORY_MESSAGES, |history| {
+                        history.min(MAX_HISTORY_MESSAGES as usize) as u8
+                    }),
+            );
+        }
+        content = &content[captures[0].len()..];
+    }
+
+    (options, content)
+}
+
+/// Numbered from 1 as in /memory list
+fn format_memories(memories: &[(ObjectId, String)]) -> String {
+    if memories.is_empty() {
+        return String::from("I don't remember anything about you.");
+    }
+
+    memories
+        .iter()
+        .enumerate()
+        .map(|(i, (_, memory))| format!("{}. {memory}", i + 1))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn memory_at(memories: &[(ObjectId, String)], number: usize) -> Option<&(ObjectId, String)> {
+    number.checked_sub(1).and_then(|i| memories.get(i))
+}
+
+/// The language of a flag emoji, made of two regional indicator symbols
+fn flag_language(emoji: &str) -> Option<&'static str> {
+    let code = emoji
+        .chars()
+        .map(|c| match c {
+            '\u{1F1E6}'..='\u{1F1FF}' => char::from_u32(c as u32 - 0x1F1E6 + 'A' as u32),
+            _ => None,
+        })
+        .collect::<Option<String>>()?;
+
+    FLAG_LANGUAGES
+        .iter()
+        .find(|(flag, _)| *flag == code)
+        .map(|(_, language)| *language)
+}
+
+/// Turns citations like `[3]` into links to the cited messages
+fn link_citations(pattern: &Regex, text: &str, links: &[String]) -> String {
+    pattern
+        .replace_all(text, |captures: &regex::Captures| {
+            let link = captures[1]
+                .parse::<usize>()
+                .ok()
+                .and_then(|n| links.get(n.checked_sub(1)?));
+            match link {
+                Some(link) => format!("[{}](<{link}>)", &captures[1]),
+                None => captures[0].to_owned(),
+            }
+        })
+        .into_owned()
+}
+
+fn flush_due(elapsed: Duration, pending: usize) -> bool {
+    elapsed >= FLUSH_INTERVAL || (pending >= FLUSH_SIZE && elapsed >= MIN_FLUSH_INTERVAL)
+}
+
+/// Midnight in JST, when the daily quotas reset
+fn start_of_today() -> chrono::DateTime<chrono::Utc> {
+    let today = chrono::Utc::now().with_timezone(&Tokyo).date_naive();
+
+    Tokyo
+        .from_local_datetime(&today.and_time(chrono::NaiveTime::MIN))
+        .single()
+        .map(|midnight| midnight.to_utc())
+        .unwrap_or(chrono::Utc::now())
+}
+
+fn total_tokens(usage: &[(String, Usage)]) -> u64 {
+    usage.iter().map(|(_, usage)| usage.total()).sum()
+}
+
+fn describe_usage(usage: &[(String, Usage)]) -> String {
+    if usage.is_empty() {
+        return String::from("- none");
+    }
+
+    usage
+        .iter()
+        .map(|(model, usage)| {
+            format!(
+                "- {model}: {} prompt + {} completion tokens",
+                usage.prompt_tokens, usage.completion_tokens
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The tokens of the message as `to_chat_message` would send it, without
+/// downloading its attachments
+fn estimate_message_tokens(message: &Message) -> usize {
+    let attachments = message
+        .attachments
+        .iter()
+        .map(|attachment| {
+            if is_image(attachment) {
+                IMAGE_TOKENS
+            } else if is_document(attachment) {
+                document::estimate_tokens(attachment)
+            } else {
+                0
+            }
+        })
+        .sum::<usize>();
+
+    estimate_tokens(&ChatMessage::text(Role::User, message.content.as_str())) + attachments
+}
+
+fn is_image(attachment: &Attachment) -> bool {
+    attachment.height.is_some() && attachment.width.is_some() && attachment.content_type.is_some()
+}
+
+/// Set the system prompt of this server ({server}, {now} and {user} are replaced)
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_GUILD")]
+pub async fn set_system_prompt(
+    ctx: Context<'_>,
+    #[description = "prompt"] prompt: String,
+    #[description = "Only for this channel"] channel: Option<bool>,
+) -> Result<(), Error> {
+    ctx.data()
+        .chat
+        .set_system_prompt(ctx, prompt, channel)
+        .await
+}
+
+/// Reset the system prompt of this server
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_GUILD")]
+pub async fn reset_system_prompt(
+    ctx: Context<'_>,
+    #[description = "Only for this channel"] channel: Option<bool>,
+) -> Result<(), Error> {
+    ctx.data().chat.reset_system_prompt(ctx, channel).await
+}
+
+/// Show the system prompt used in this channel
+#[poise::command(slash_command, guild_only)]
+pub async fn show_system_prompt(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.data().chat.show_system_prompt(ctx).await
+}
+
+/// Summarize recent messages of this channel
+#[poise::command(slash_command)]
+pub async fn summarize(
+    ctx: Context<'_>,
+    #[description = "Summarize the last hours (default: since your last message)"]
+    #[min = 1]
+    #[max = 168]
+    hours: Option<u32>,
+    #[description = "Only you can see the summary (default: true)"] ephemeral: Option<bool>,
+) -> Result<(), Error> {
+    ctx.data()
+        .chat
+        .summarize(ctx, hours, ephemeral.unwrap_or(true))
+        .await
+}
+
+/// Translate text (between Japanese and English by default)
+#[poise::command(slash_command)]
+pub async fn translate(
+    ctx: Context<'_>,
+    #[description = "Text to translate"] text: String,
+    #[description = "Target language"] language: Option<String>,
+    #[description = "Only you can see the translation (default: false)"] ephemeral: Option<bool>,
+) -> Result<(), Error> {
+    ctx.data()
+        .chat
+        .translate(ctx, text, language, ephemeral.unwrap_or(false))
+        .await
+}
+
+/// Manage what the bot remembers about you
+#[poise::command(
+    slash_command,
+    subcommands("memory_list", "memory_forget", "memory_forget_all"),
+    subcommand_required
+)]
+pub async fn memory(_ctx: Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+/// List what the bot remembers about you
+#[poise::command(slash_command, rename = "list")]
+pub async fn memory_list(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.data().chat.list_memories(ctx).await
+}
+
+/// Make the bot forget something about you
+#[poise::command(slash_command, rename = "forget")]
+pub async fn memory_forget(
+    ctx: Context<'_>,
+    #[description = "Number shown by /memory list"]
+    #[min = 1]
+    number: usize,
+) -> Result<(), Error> {
+    ctx.data().chat.forget_memory(ctx, number).await
+}
+
+/// Make the bot forget everything about you
+#[poise::command(slash_command, rename = "forget_all")]
+pub async fn memory_forget_all(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.data().chat.forget_all_memories(ctx).await
+}
+
+/// Include recent messages of this channel when the bot is mentioned
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_GUILD")]
+pub async fn channel_history(
+    ctx: Context<'_>,
+    #[description = "Number of messages (0 to disable)"]
+    #[max = 100]
+    messages: Option<u8>,
+) -> Result<(), Error> {
+    ctx.data().chat.channel_history(ctx, messages).await
+}
+
+/// Show or set the LLM model used to reply to you
+#[poise::command(slash_command)]
+pub async fn model(
+    ctx: Context<'_>,
+    #[description = "Model name or alias (\"default\" to reset)"] name: Option<String>,
+    #[description = "Who the setting applies to (default: User)"] scope: Option<ModelScope>,
+) -> Result<(), Error> {
+    ctx.data().chat.model(ctx, name, scope).await
+}
+
+/// Ask the LLM privately
+#[poise::command(slash_command)]
+pub async fn ask(
+    ctx: Context<'_>,
+    #[description = "Question"] prompt: String,
+    #[description = "Image, text or PDF file to ask about"] attachment: Option<Attachment>,
+    #[description = "Model name or alias"] model: Option<String>,
+    #[description = "Only you can see the answer (default: true)"] ephemeral: Option<bool>,
+) -> Result<(), Error> {
+    ctx.data()
+        .chat
+        .ask(ctx, prompt, attachment, model, ephemeral.unwrap_or(true))
+        .await
+}
+
+/// Chat with the LLM in this channel
+#[poise::command(slash_command)]
+pub async fn chat(
+    ctx: Context<'_>,
+    #[description = "Message"] prompt: String,
+    #[description = "Image, text or PDF file to talk about"] attachment: Option<Attachment>,
+    #[description = "Model name or alias"] model: Option<String>,
+    #[description = "Only you can see the answer (default: false)"] ephemeral: Option<bool>,
+) -> Result<(), Error> {
+    ctx.data()
+        .chat
+        .ask(ctx, prompt, attachment, model, ephemeral.unwrap_or(false))
+        .await
+}
+
+/// Start a conversation with the LLM in a new thread
+#[poise::command(slash_command, guild_only)]
+pub async fn thread_chat(
+    ctx: Context<'_>,
+    #[description = "Thread name"] name: Option<String>,
+) -> Result<(), Error> {
+    ctx.data().chat.thread_chat(ctx, name).await
+}
+
+/// Show how many LLM tokens you or this server used
+#[poise::command(slash_command)]
+pub async fn usage(
+    ctx: Context<'_>,
+    #[description = "Show the usage of the whole server"] server: Option<bool>,
+) -> Result<(), Error> {
+    ctx.data().chat.usage(ctx, server).await
+}
+
+/// Appends a page of messages, newest first, until the cutoff of
+/// `get_recent_messages` or the limit, returning whether either was reached
+fn take_recent(
+    page: Vec<Message>,
+    since: Option<chrono::DateTime<chrono::Utc>>,
+    user_id: UserId,
+    messages: &mut Vec<Message>,
+) -> bool {
+    for message in page {
+        let reached = match since {
+            Some(since) => *message.timestamp < since,
+            None => message.author.id == user_id,
+        };
+        if reached || messages.len() >= MAX_SUMMARY_MESSAGES {
+            return true;
+        }
+        messages.push(message);
+    }
+
+    messages.len() >= MAX_SUMMARY_MESSAGES
+}
+
+/// The index of the oldest line from which the rest fit in `budget`
+fn transcript_start(lines: &[String], budget: usize) -> usize {
+    let mut tokens = 0;
+    let mut start = lines.len();
+    while start > 0 {
+        tokens += estimate_tokens(&ChatMessage::text(Role::User, lines[start - 1].as_str()));
+        if tokens > budget {
+            break;
+        }
+        start -= 1;
+    }
+
+    start
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+    use poise::serenity_prelude::{json, Timestamp};
+
+    #[test]
+    fn maps_flags_to_languages() {
+        assert_eq!(flag_language("🇯🇵"), Some("Japanese"));
+        assert_eq!(flag_language("🇬🇧"), Some("English"));
+        assert_eq!(flag_language("🇦🇶"), None);
+        assert_eq!(flag_language("👍"), None);
+    }
+
+    #[test]
+    fn numbers_memories() {
+        let first = ObjectId::new();
+        let memories = parse_memories(&[
+            doc! { "_id": first, "content": "Likes tea" },
+            doc! { "_id": ObjectId::new(), "content": "Lives in Osaka" },
+        ])
+        .unwrap();
+
+        assert_eq!(
+            format_memories(&memories),
+            "1. Likes tea\n2. Lives in Osaka"
+        );
+        assert_eq!(format_memories(&[]), "I don't remember anything about you.");
+        assert_eq!(
+            memory_at(&memories, 1),
+            Some(&(first, "Likes tea".to_owned()))
+        );
+        assert_eq!(memory_at(&memories, 0), None);
+        assert_eq!(memory_at(&memories, 3), None);
+        assert!(parse_memories(&[doc! { "content": "no id" }]).is_err());
+    }
+
+    #[test]
+    fn translates_each_language_once() {
+        let translations = Translations::default();
+        let message_id = MessageId::new(10);
+
+        assert!(translations.claim(message_id, "English"));
+        assert!(!translations.claim(message_id, "English"));
+        assert!(translations.claim(message_id, "Japanese"));
+
+        translations.release(message_id, "English");
+        assert!(translations.claim(message_id, "English"));
+    }
+
+    #[test]
+    fn forgets_oldest_translations() {
+        let translations = Translations::default();
+        for id in 1..=MAX_TRANSLATIONS as u64 + 1 {
+            assert!(translations.claim(MessageId::new(id), "English"));
+        }
+
+        assert!(translations.claim(MessageId::new(1), "English"));
+        assert!(!translations.claim(MessageId::new(MAX_TRANSLATIONS as u64 + 1), "English"));
+    }
+
+    #[test]
+    fn parses_options() {
+        let pattern = Regex::new(OPTION_PATTERN).unwrap();
+
+        assert_eq!(
+            parse_options(&pattern, "model:fast history:200 hello"),
+            (
+                Options {
+                    model: Some("fast"),
+                    history: Some(MAX_HISTORY_MESSAGES),
+                },
+                "hello"
+            )
+        );
+        assert_eq!(
+            parse_options(&pattern, "history:"),
+            (
+                Options {
+                    model: None,
+                    history: Some(DEFAULT_HISTORY_MESSAGES),
+                },
+                ""
+            )
+        );
+        assert_eq!(
+            parse_options(&pattern, "history:2024年の予定"),
+            (Options::default(), "history:2024年の予定")
+        );
+        assert_eq!(
+            parse_options(&pattern, "history:ten"),
+            (Options::default(), "history:ten")
+        );
+    }
+
+    fn http_error(status: u16, retry_after: Option<u64>) -> Error {
+        ProviderError::Http {
+            status,
+            message: String::from("error"),
+            retry_after,
+        }
+        .into()
+    }
+
+    #[test]
+    fn describes_provider_errors() {
+        assert_eq!(
+            describe_error(&http_error(401, None)),
+            "The LLM API rejected the credentials. Please tell the bot admin."
+        );
+        assert_eq!(
+            describe_error(&http_error(429, Some(20))),
+            "The LLM API is rate limited. Please try again in 20 seconds."
+        );
+        assert_eq!(
+            describe_error(&http_error(503, None)),
+            "The LLM API is unavailable (HTTP 503). Please try again in a moment."
+        );
+        assert_eq!(
+            describe_error(&ProviderError::Empty.into()),
+            "The model returned an empty response. Please regenerate it."
+        );
+        assert_eq!(
+            describe_error(&CompletionError::Api(String::from("Overloaded")).into()),
+            "The LLM API is busy. Please try again in a moment."
+        );
+    }
+
+    #[tokio::test]
+    async fn describes_timeouts() {
+        // accepts connections without ever responding
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let error = reqwest::Client::new()
+            .get(format!("http://{}", listener.local_addr().unwrap()))
+            .timeout(Duration::from_millis(50))
+            .send()
+            .await
+            .unwrap_err();
+
+        assert_eq!(
+            describe_error(&error.into()),
+            "The LLM API did not respond in time. Please try again."
+        );
+    }
+
+    #[test]
+    fn links_citations() {
+        let pattern = Regex::new(r"\[(\d+)\]").unwrap();
+        let links = [String::from("https://a/1"), String::from("https://a/2")];
+
+        assert_eq!(
+            link_citations(&pattern, "- Release on Friday [2]\n- [0] [3] [x]", &links),
+            "- Release on Friday [2](<https://a/2>)\n- [0] [3] [x]"
+        );
+    }
+
+    fn message(author: u64, timestamp: i64) -> Message {
+        let mut message = Message::default();
+        message.author.id = UserId::new(author);
+        message.timestamp = Timestamp::from_unix_timestamp(timestamp).unwrap();
+        message
+    }
+
+    #[test]
+    fn takes_messages_since_last_own_message() {
+        let page = vec![
+            message(2, 30),
+            message(3, 20),
+            message(1, 10),
+            message(2, 0),
+        ];
+        let mut messages = vec![];
+
+        assert!(take_recent(page, None, UserId::new(1), &mut messages));
+        assert_eq!(messages.len(), 2);
+
+        let page = vec![message(2, 30), message(3, 20)];
+        let mut messages = vec![];
+        assert!(!take_recent(page, None, UserId::new(1), &mut messages));
+        assert_eq!(messages.len(), 2);
+    }
+
+    #[test]
+    fn takes_messages_since_time() {
+        let since = chrono::DateTime::from_timestamp(15, 0);
+        let page = vec![message(1, 30), message(2, 20), message(1, 10)];
+        let mut messages = vec![];
+
+        assert!(take_recent(page, since, UserId::new(1), &mut messages));
+        assert_eq!(messages.len(), 2);
+    }
+
+    #[test]
+    fn stops_at_summary_limit() {
+        let mut messages = vec![message(2, 1000); MAX_SUMMARY_MESSAGES - 1];
+        let page = vec![message(2, 30), message(2, 20)];
+
+        assert!(take_recent(page, None, UserId::new(1), &mut messages));
+        assert_eq!(messages.len(), MAX_SUMMARY_MESSAGES);
+    }
+
+    #[test]
+    fn estimates_attachments() {
+        let mut message = message(1, 0);
+        message.content = String::from("aaaaaaaa");
+        assert_eq!(estimate_message_tokens(&message), 2);
+
+        let attachment = |filename: &str, extra: json::Value| -> Attachment {
+            let mut value = json::json!({
+                "id": "1",
+                "filename": filename,
+                "size": 100,
+                "url": "https://cdn/a",
+                "proxy_url": "https://media/a",
+            });
+            value
+                .as_object_mut()
+                .unwrap()
+                .extend(extra.as_object().unwrap().clone());
+            json::from_value(value).unwrap()
+        };
+        message.attachments = vec![
+            attachment(
+                "a.png",
+                json::json!({ "width": 100, "height": 100, "content_type": "image/png" }),
+            ),
+            attachment("notes.txt", json::json!({})),
+        ];
+
+        assert_eq!(estimate_message_tokens(&message), 2 + IMAGE_TOKENS + 100);
+    }
+
+    #[test]
+    fn trims_transcript_to_budget() {
+        // 8 ASCII characters are 2 tokens each
+        let lines = vec![String::from("aaaaaaaa"); 5];
+
+        assert_eq!(transcript_start(&lines, 100), 0);
+        assert_eq!(transcript_start(&lines, 6), 2);
+        assert_eq!(transcript_start(&lines, 1), 5);
     }
 }
@@ -11,6 +11,17 @@ use crate::Error;
 pub struct Db {
     speaker_coll: Collection<Document>,
     remind_coll: Collection<Document>,
+    voice_session_coll: Collection<Document>,
+    system_prompt_coll: Collection<Document>,
+    model_coll: Collection<Document>,
+    channel_history_coll: Collection<Document>,
+    reply_coll: Collection<Document>,
+    thread_coll: Collection<Document>,
+    thread_message_coll: Collection<Document>,
+    usage_coll: Collection<Document>,
+    memory_coll: Collection<Document>,
+    indexed_channel_coll: Collection<Document>,
+    embedding_coll: Collection<Document>,
 }
 
 pub async fn build_db() -> Result<Db, Error> {
@@ -19,10 +30,32 @@ pub async fn build_db() -> Result<Db, Error> {
     let database = client.database("bot");
     let speaker_coll = database.collection("speakers");
     let remind_coll = database.collection("reminds");
+    let voice_session_coll = database.collection("voice_sessions");
+    let system_prompt_coll = database.collection("system_prompts");
+    let model_coll = database.collection("models");
+    let channel_history_coll = database.collection("channel_history");
+    let reply_coll = database.collection("replies");
+    let thread_coll = database.collection("threads");
+    let thread_message_coll = database.collection("thread_messages");
+    let usage_coll = database.collection("usage");
+    let memory_coll = database.collection("memories");
+    let indexed_channel_coll = database.collection("indexed_channels");
+    let embedding_coll = database.collection("embeddings");
 
     Ok(Db {
         speaker_coll,
         remind_coll,
+        voice_session_coll,
+        system_prompt_coll,
+        model_coll,
+        channel_history_coll,
+        reply_coll,
+        thread_coll,
+        thread_message_coll,
+        usage_coll,
+        memory_coll,
+        indexed_channel_coll,
+        embedding_coll,
     })
 }
 
@@ -53,23 +86,51 @@ impl Db {
         &self,
         user_id: u64,
         channel_id: u64,
+        guild_id: Option<u64>,
         content: String,
         remind_at: chrono::DateTime<chrono::Utc>,
+        voice: bool,
     ) -> Result<(), Error> {
         let remind_at = bson::DateTime::parse_rfc3339_str(remind_at.to_rfc3339())?;
 
-        let reminder = doc! {
+        let mut reminder = doc! {
             "user_id": user_id.to_string(),
             "channel_id": channel_id.to_string(),
             "content": content,
             "remind_at": remind_at,
+            "voice": voice,
         };
+        if let Some(guild_id) = guild_id {
+            reminder.insert("guild_id", guild_id.to_string());
+        }
 
         self.remind_coll.insert_one(reminder).await?;
 
         Ok(())
     }
 
+    pub async fn get_user_reminders(&self, user_id: u64) -> Result<Vec<Document>, Error> {
+        let filter = doc! { "user_id": user_id.to_string() };
+        let cursor = self
+            .remind_coll
+            .find(filter)
+            .sort(doc! { "remind_at": 1 })
+            .await?;
+        let reminds: Vec<Document> = cursor.try_collect::<Vec<Document>>().await?;
+
+        Ok(reminds)
+    }
+
+    pub async fn remove_user_reminder(&self, user_id: u64, id: &str) -> Result<bool, Error> {
+        let id = bson::oid::ObjectId::parse_str(id)?;
+        let result = self
+            .remind_coll
+            .delete_one(doc! { "_id": id, "user_id": user_id.to_string() })
+            .await?;
+
+        Ok(result.deleted_count > 0)
+    }
+
     pub async fn get_reminders(&self) -> Result<Vec<Document>, Error> {
         let begin = chrono::Utc::now() - chrono::Duration::minutes(1);
         let begin = bson::DateTime::parse_rfc3339_str(begin.to_rfc3339())?;
@@ -108,4 +169,430 @@ impl Db {
 
         Ok(reminds)
     }
+
+    pub async fn save_voice_session(
+        &self,
+        guild_id: u64,
+        voice_channel_id: u64,
+        text_channel_id: u64,
+    ) -> Result<(), Error> {
+        let filter = doc! { "guild_id": guild_id.to_string() };
+        let update = doc! {
+            "$set": {
+                "voice_channel_id": voice_channel_id.to_string(),
+                "text_channel_id": text_channel_id.to_string(),
+            }
+        };
+
+        self.voice_session_coll
+            .update_one(filter, update)
+            .upsert(true)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_voice_sessions(&self) -> Result<Vec<Document>, Error> {
+        let cursor = self.voice_session_coll.find(doc! {}).await?;
+        let sessions: Vec<Document> = cursor.try_collect::<Vec<Document>>().await?;
+
+        Ok(sessions)
+    }
+
+    pub async fn remove_voice_session(&self, guild_id: u64) -> Result<(), Error> {
+        self.voice_session_coll
+            .delete_one(doc! { "guild_id": guild_id.to_string() })
+            .await?;
+
+        Ok(())
+    }
+
+    /// `channel_id: None` refers to the guild-wide system prompt
+    pub async fn get_system_prompt(
+        &self,
+        guild_id: u64,
+        channel_id: Option<u64>,
+    ) -> Result<Option<String>, Error> {
+        let filter = system_prompt_filter(guild_id, channel_id);
+        let prompt = self.system_prompt_coll.find_one(filter).await?;
+
+        match prompt {
+            Some(prompt) => Ok(Some(prompt.get_str("prompt")?.to_owned())),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn update_system_prompt(
+        &self,
+        guild_id: u64,
+        channel_id: Option<u64>,
+        prompt: String,
+    ) -> Result<(), Error> {
+        let filter = system_prompt_filter(guild_id, channel_id);
+        let update = doc! { "$set": { "prompt": prompt } };
+
+        self.system_prompt_coll
+            .update_one(filter, update)
+            .upsert(true)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn remove_system_prompt(
+        &self,
+        guild_id: u64,
+        channel_id: Option<u64>,
+    ) -> Result<bool, Error> {
+        let filter = system_prompt_filter(guild_id, channel_id);
+        let result = self.system_prompt_coll.delete_one(filter).await?;
+
+        Ok(result.deleted_count > 0)
+    }
+
+    /// `scope` is one of "user", "channel" and "guild"
+    pub async fn get_model(&self, scope: &str, id: u64) -> Result<Option<String>, Error> {
+        let filter = doc! { "scope": scope, "id": id.to_string() };
+        let model = self.model_coll.find_one(filter).await?;
+
+        match model {
+            Some(model) => Ok(Some(model.get_str("model")?.to_owned())),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn update_model(&self, scope: &str, id: u64, model: &str) -> Result<(), Error> {
+        let filter = doc! { "scope": scope, "id": id.to_string() };
+        let update = doc! { "$set": { "model": model } };
+
+        self.model_coll
+            .update_one(filter, update)
+            .upsert(true)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn remove_model(&self, scope: &str, id: u64) -> Result<(), Error> {
+        let filter = doc! { "scope": scope, "id": id.to_string() };
+        self.model_coll.delete_one(filter).await?;
+
+        Ok(())
+    }
+
+    pub async fn get_channel_history(&self, channel_id: u64) -> Result<Option<u8>, Error> {
+        let filter = doc! { "channel_id": channel_id.to_string() };
+        let history = self.channel_history_coll.find_one(filter).await?;
+
+        match history {
+            Some(history) => Ok(Some(history.get_i32("messages")? as u8)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn update_channel_history(&self, channel_id: u64, messages: u8) -> Result<(), Error> {
+        let filter = doc! { "channel_id": channel_id.to_string() };
+        let update = doc! { "$set": { "messages": (messages as i32) } };
+
+        self.channel_history_coll
+            .update_one(filter, update)
+            .upsert(true)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn remove_channel_history(&self, channel_id: u64) -> Result<(), Error> {
+        let filter = doc! { "channel_id": channel_id.to_string() };
+        self.channel_history_coll.delete_one(filter).await?;
+
+        Ok(())
+    }
+
+    pub async fn add_reply(
+        &self,
+        message_id: u64,
+        channel_id: u64,
+        user_id: u64,
+        model: &str,
+    ) -> Result<(), Error> {
+        let reply = doc! {
+            "message_id": message_id.to_string(),
+            "channel_id": channel_id.to_string(),
+            "user_id": user_id.to_string(),
+            "model": model,
+            "created_at": bson::DateTime::now(),
+        };
+
+        self.reply_coll.insert_one(reply).await?;
+
+        Ok(())
+    }
+
+    pub async fn add_thread(
+        &self,
+        thread_id: u64,
+        guild_id: u64,
+        user_id: u64,
+    ) -> Result<(), Error> {
+        let thread = doc! {
+            "thread_id": thread_id.to_string(),
+            "guild_id": guild_id.to_string(),
+            "user_id": user_id.to_string(),
+            "created_at": bson::DateTime::now(),
+        };
+
+        self.thread_coll.insert_one(thread).await?;
+
+        Ok(())
+    }
+
+    pub async fn get_threads(&self) -> Result<Vec<Document>, Error> {
+        let cursor = self.thread_coll.find(doc! {}).await?;
+        let threads: Vec<Document> = cursor.try_collect::<Vec<Document>>().await?;
+
+        Ok(threads)
+    }
+
+    /// `role` is either "user" or "assistant". `message_id` is the user's
+    /// message that the question and its reply belong to.
+    pub async fn add_thread_message(
+        &self,
+        thread_id: u64,
+        message_id: u64,
+        role: &str,
+        content: &str,
+    ) -> Result<(), Error> {
+        let message = doc! {
+            "thread_id": thread_id.to_string(),
+            "message_id": message_id.to_string(),
+            "role": role,
+            "content": content,
+            "created_at": bson::DateTime::now(),
+        };
+
+        self.thread_message_coll.insert_one(message).await?;
+
+        Ok(())
+    }
+
+    /// Replaces the content of the reply to the user's message
+    pub async fn update_thread_reply(
+        &self,
+        thread_id: u64,
+        message_id: u64,
+        content: &str,
+    ) -> Result<(), Error> {
+        let filter = doc! {
+            "thread_id": thread_id.to_string(),
+            "message_id": message_id.to_string(),
+            "role": "assistant",
+        };
+        let update = doc! { "$set": { "content": content } };
+
+        self.thread_message_coll.update_one(filter, update).await?;
+
+        Ok(())
+    }
+
+    pub async fn get_thread_messages(&self, thread_id: u64) -> Result<Vec<Document>, Error> {
+        let filter = doc! { "thread_id": thread_id.to_string() };
+        let cursor = self
+            .thread_message_coll
+            .find(filter)
+            .sort(doc! { "created_at": 1 })
+            .await?;
+        let messages: Vec<Document> = cursor.try_collect::<Vec<Document>>().await?;
+
+        Ok(messages)
+    }
+
+    pub async fn add_memory(&self, user_id: u64, content: &str) -> Result<(), Error> {
+        let memory = doc! {
+            "user_id": user_id.to_string(),
+            "content": content,
+            "created_at": bson::DateTime::now(),
+        };
+
+        self.memory_coll.insert_one(memory).await?;
+
+        Ok(())
+    }
+
+    pub async fn get_memories(&self, user_id: u64) -> Result<Vec<Document>, Error> {
+        let filter = doc! { "user_id": user_id.to_string() };
+        let cursor = self
+            .memory_coll
+            .find(filter)
+            .sort(doc! { "created_at": 1 })
+            .await?;
+        let memories: Vec<Document> = cursor.try_collect::<Vec<Document>>().await?;
+
+        Ok(memories)
+    }
+
+    pub async fn remove_memory(
+        &self,
+        user_id: u64,
+        id: bson::oid::ObjectId,
+    ) -> Result<bool, Error> {
+        let result = self
+            .memory_coll
+            .delete_one(doc! { "_id": id, "user_id": user_id.to_string() })
+            .await?;
+
+        Ok(result.deleted_count > 0)
+    }
+
+    pub async fn remove_memories(&self, user_id: u64) -> Result<u64, Error> {
+        let filter = doc! { "user_id": user_id.to_string() };
+        let result = self.memory_coll.delete_many(filter).await?;
+
+        Ok(result.deleted_count)
+    }
+
+    pub async fn add_indexed_channel(&self, guild_id: u64, channel_id: u64) -> Result<(), Error> {
+        let filter = doc! { "channel_id": channel_id.to_string() };
+        let update = doc! { "$set": { "guild_id": guild_id.to_string() } };
+
+        self.indexed_channel_coll
+            .update_one(filter, update)
+            .upsert(true)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_indexed_channels(&self) -> Result<Vec<Document>, Error> {
+        let cursor = self.indexed_channel_coll.find(doc! {}).await?;
+        let channels: Vec<Document> = cursor.try_collect::<Vec<Document>>().await?;
+
+        Ok(channels)
+    }
+
+    pub async fn remove_indexed_channel(&self, channel_id: u64) -> Result<bool, Error> {
+        let filter = doc! { "channel_id": channel_id.to_string() };
+        let result = self.indexed_channel_coll.delete_one(filter).await?;
+
+        Ok(result.deleted_count > 0)
+    }
+
+    /// Replaces the embedding of a message indexed before
+    pub async fn add_embedding(
+        &self,
+        guild_id: u64,
+        channel_id: u64,
+        message_id: u64,
+        author: &str,
+        content: &str,
+        vector: &[f32],
+    ) -> Result<(), Error> {
+        let filter = doc! { "message_id": message_id.to_string() };
+        let vector = vector.iter().map(|value| *value as f64).collect::<Vec<_>>();
+        let update = doc! {
+            "$set": {
+                "guild_id": guild_id.to_string(),
+                "channel_id": channel_id.to_string(),
+                "author": author,
+                "content": content,
+                "vector": vector,
+            }
+        };
+
+        self.embedding_coll
+            .update_one(filter, update)
+            .upsert(true)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_embeddings(&self) -> Result<Vec<Document>, Error> {
+        let cursor = self.embedding_coll.find(doc! {}).await?;
+        let embeddings: Vec<Document> = cursor.try_collect::<Vec<Document>>().await?;
+
+        Ok(embeddings)
+    }
+
+    pub async fn remove_embeddings(&self, message_ids: &[u64]) -> Result<(), Error> {
+        let message_ids = message_ids
+            .iter()
+            .map(|message_id| message_id.to_string())
+            .collect::<Vec<_>>();
+        let filter = doc! { "message_id": { "$in": message_ids } };
+        self.embedding_coll.delete_many(filter).await?;
+
+        Ok(())
+    }
+
+    pub async fn remove_channel_embeddings(&self, channel_id: u64) -> Result<u64, Error> {
+        let filter = doc! { "channel_id": channel_id.to_string() };
+        let result = self.embedding_coll.delete_many(filter).await?;
+
+        Ok(result.deleted_count)
+    }
+
+    pub async fn add_usage(
+        &self,
+        user_id: u64,
+        guild_id: Option<u64>,
+        model: &str,
+        prompt_tokens: u64,
+        completion_tokens: u64,
+    ) -> Result<(), Error> {
+        let mut usage = doc! {
+            "user_id": user_id.to_string(),
+            "model": model,
+            "prompt_tokens": prompt_tokens as i64,
+            "completion_tokens": completion_tokens as i64,
+            "created_at": bson::DateTime::now(),
+        };
+        if let Some(guild_id) = guild_id {
+            usage.insert("guild_id", guild_id.to_string());
+        }
+
+        self.usage_coll.insert_one(usage).await?;
+
+        Ok(())
+    }
+
+    /// Tokens used since the time, summed up per model.
+    /// `scope` is either "user_id" or "guild_id"
+    pub async fn get_usage(
+        &self,
+        scope: &str,
+        id: u64,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Vec<Document>, Error> {
+        let since = bson::DateTime::parse_rfc3339_str(since.to_rfc3339())?;
+        let pipeline = vec![
+            doc! { "$match": { scope: id.to_string(), "created_at": { "$gte": since } } },
+            doc! {
+                "$group": {
+                    "_id": "$model",
+                    "prompt_tokens": { "$sum": "$prompt_tokens" },
+                    "completion_tokens": { "$sum": "$completion_tokens" },
+                },
+            },
+            doc! { "$sort": { "_id": 1 } },
+        ];
+
+        let cursor = self.usage_coll.aggregate(pipeline).await?;
+        let usage: Vec<Document> = cursor.try_collect::<Vec<Document>>().await?;
+
+        Ok(usage)
+    }
+}
+
+fn system_prompt_filter(guild_id: u64, channel_id: Option<u64>) -> Document {
+    match channel_id {
+        Some(channel_id) => doc! {
+            "guild_id": guild_id.to_string(),
+            "channel_id": channel_id.to_string(),
+        },
+        None => doc! {
+            "guild_id": guild_id.to_string(),
+            "channel_id": null,
+        },
+    }
 }
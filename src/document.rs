@@ -0,0 +1,148 @@
+use std::path::Path;
+
+use poise::serenity_prelude::Attachment;
+
+use crate::Error;
+
+/// Larger files are not downloaded
+const MAX_FILE_SIZE: u32 = 10 * 1024 * 1024;
+/// Extracted text is cut off after this many characters
+const MAX_TEXT_LENGTH: usize = 20_000;
+const TEXT_EXTENSIONS: &[&str] = &[
+    "txt", "md", "csv", "tsv", "json", "jsonl", "yaml", "yml", "toml", "xml", "html", "css", "log",
+    "ini", "sql", "sh", "rs", "py", "js", "ts", "jsx", "tsx", "go", "java", "kt", "c", "h", "cpp",
+    "hpp", "cs", "rb", "php", "swift", "lua",
+];
+
+#[derive(Debug, PartialEq)]
+enum Kind {
+    Text,
+    Pdf,
+}
+
+/// Whether the text of the attachment can be given to the LLM
+pub fn is_document(attachment: &Attachment) -> bool {
+    kind(&attachment.filename, attachment.content_type.as_deref()).is_some()
+}
+
+/// A file that could not be read, with a reason that can be shown to users
+#[derive(Debug)]
+pub struct DocumentError {
+    filename: String,
+    reason: String,
+}
+
+impl std::fmt::Display for DocumentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Could not read `{}`: {}", self.filename, self.reason)
+    }
+}
+
+impl std::error::Error for DocumentError {}
+
+/// Downloads the attachment and returns its text in a code block labelled
+/// with the file name
+pub async fn extract_text(attachment: &Attachment) -> Result<String, Error> {
+    read(attachment).await.map_err(|e| {
+        DocumentError {
+            filename: attachment.filename.clone(),
+            reason: e.to_string(),
+        }
+        .into()
+    })
+}
+
+async fn read(attachment: &Attachment) -> Result<String, Error> {
+    let Some(kind) = kind(&attachment.filename, attachment.content_type.as_deref()) else {
+        return Err("it is not a text or PDF file".into());
+    };
+    if attachment.size > MAX_FILE_SIZE {
+        return Ok(format!(
+            "(The attached file `{}` was skipped because it is larger than {} MB)",
+            attachment.filename,
+            MAX_FILE_SIZE / 1024 / 1024
+        ));
+    }
+
+    let content = attachment.download().await?;
+    let text = match kind {
+        Kind::Text => String::from_utf8_lossy(&content).into_owned(),
+        Kind::Pdf => {
+            tokio::task::spawn_blocking(move || pdf_extract::extract_text_from_mem(&content))
+                .await??
+        }
+    };
+
+    Ok(format_document(&attachment.filename, &text))
+}
+
+/// An upper bound of the tokens the extracted text takes, before downloading
+/// the attachment
+pub fn estimate_tokens(attachment: &Attachment) -> usize {
+    (attachment.size as usize).min(MAX_TEXT_LENGTH)
+}
+
+fn kind(filename: &str, content_type: Option<&str>) -> Option<Kind> {
+    let extension = Path::new(filename)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_lowercase);
+    let content_type = content_type.unwrap_or("");
+
+    if extension.as_deref() == Some("pdf") || content_type.starts_with("application/pdf") {
+        return Some(Kind::Pdf);
+    }
+    if extension.is_some_and(|extension| TEXT_EXTENSIONS.contains(&extension.as_str()))
+        || content_type.starts_with("text/")
+        || content_type.starts_with("application/json")
+    {
+        return Some(Kind::Text);
+    }
+
+    None
+}
+
+fn format_document(filename: &str, text: &str) -> String {
+    let text = text.trim();
+    let (text, note) = match text.char_indices().nth(MAX_TEXT_LENGTH) {
+        Some((end, _)) => (
+            &text[..end],
+            format!(" (truncated to the first {MAX_TEXT_LENGTH} characters)"),
+        ),
+        None => (text, String::new()),
+    };
+    // a longer fence keeps code blocks in the file from closing it
+    let fence = if text.contains("```") { "````" } else { "```" };
+
+    format!("Attached file `{filename}`{note}:\n{fence}\n{text}\n{fence}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_kind() {
+        assert_eq!(kind("report.PDF", None), Some(Kind::Pdf));
+        assert_eq!(kind("main.rs", None), Some(Kind::Text));
+        assert_eq!(kind("data", Some("application/json")), Some(Kind::Text));
+        assert_eq!(
+            kind("notes", Some("text/plain; charset=utf-8")),
+            Some(Kind::Text)
+        );
+        assert_eq!(kind("photo.png", Some("image/png")), None);
+    }
+
+    #[test]
+    fn formats_document() {
+        assert_eq!(
+            format_document("a.md", "# Title\n```rs\nfn main() {}\n```\n"),
+            "Attached file `a.md`:\n````\n# Title\n```rs\nfn main() {}\n```\n````"
+        );
+
+        let long = "あ".repeat(MAX_TEXT_LENGTH + 1);
+        let formatted = format_document("long.txt", &long);
+        assert!(formatted.starts_with("Attached file `long.txt` (truncated"));
+        assert_eq!(formatted.matches('あ').count(), MAX_TEXT_LENGTH);
+    }
+}
@@ -0,0 +1,221 @@
+use poise::serenity_prelude::Attachment;
+use url::Url;
+
+use crate::Error;
+
+/// Formats all the LLM APIs accept
+const SUPPORTED_TYPES: &[&str] = &["image/png", "image/jpeg", "image/webp"];
+/// The smallest limit of the LLM APIs on the size of an image
+const MAX_IMAGE_SIZE: usize = 5 * 1024 * 1024;
+
+pub struct Image {
+    pub media_type: String,
+    pub data: Vec<u8>,
+}
+
+/// What to request from Discord's media proxy
+#[derive(Debug, PartialEq)]
+struct Conversion {
+    width: u32,
+    height: u32,
+    format: &'static str,
+}
+
+/// An image that could not be sent, with a reason that can be shown to users
+#[derive(Debug)]
+pub struct ImageError {
+    filename: String,
+    reason: String,
+}
+
+impl std::fmt::Display for ImageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Could not send `{}`: {}", self.filename, self.reason)
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+/// Downloads the image, scaled down to fit in `max_dimension` and converted
+/// to PNG or JPEG by Discord's media proxy when needed
+pub async fn download(
+    http_client: &reqwest::Client,
+    attachment: &Attachment,
+    max_dimension: u32,
+) -> Result<Image, Error> {
+    fetch(http_client, attachment, max_dimension)
+        .await
+        .map_err(|e| {
+            ImageError {
+                filename: attachment.filename.clone(),
+                reason: e.to_string(),
+            }
+            .into()
+        })
+}
+
+async fn fetch(
+    http_client: &reqwest::Client,
+    attachment: &Attachment,
+    max_dimension: u32,
+) -> Result<Image, Error> {
+    let content_type = attachment.content_type.as_deref().unwrap_or("image/png");
+    let conversion = match (attachment.width, attachment.height) {
+        (Some(width), Some(height)) => conversion(
+            content_type,
+            width,
+            height,
+            attachment.size as usize,
+            max_dimension,
+        ),
+        _ => None,
+    };
+
+    let image = match conversion {
+        Some(conversion) => {
+            let url = proxy_url(&attachment.proxy_url, &conversion)?;
+            Image {
+                media_type: format!("image/{}", conversion.format),
+                data: http_client
+                    .get(url)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .bytes()
+                    .await?
+                    .to_vec(),
+            }
+        }
+        None => Image {
+            media_type: content_type.to_owned(),
+            data: attachment.download().await?,
+        },
+    };
+
+    if image.data.len() > MAX_IMAGE_SIZE {
+        return Err(format!(
+            "it is larger than {} MB even after resizing",
+            MAX_IMAGE_SIZE / 1024 / 1024
+        )
+        .into());
+    }
+
+    Ok(image)
+}
+
+/// None when the original can be sent as it is
+fn conversion(
+    content_type: &str,
+    width: u32,
+    height: u32,
+    size: usize,
+    max_dimension: u32,
+) -> Option<Conversion> {
+    let supported = SUPPORTED_TYPES.contains(&content_type);
+    let fits = width <= max_dimension && height <= max_dimension;
+    if supported && fits && size <= MAX_IMAGE_SIZE {
+        return None;
+    }
+
+    let format = match content_type {
+        // the first frame keeps its transparency
+        "image/gif" => "png",
+        "image/png" if size <= MAX_IMAGE_SIZE => "png",
+        "image/webp" if size <= MAX_IMAGE_SIZE => "webp",
+        _ => "jpeg",
+    };
+    let (width, height) = if fits {
+        (width, height)
+    } else {
+        let scale = max_dimension as f64 / width.max(height) as f64;
+        (
+            ((width as f64 * scale).round() as u32).max(1),
+            ((height as f64 * scale).round() as u32).max(1),
+        )
+    };
+
+    Some(Conversion {
+        width,
+        height,
+        format,
+    })
+}
+
+fn proxy_url(proxy_url: &str, conversion: &Conversion) -> Result<Url, Error> {
+    let mut url = Url::parse(proxy_url)?;
+    url.query_pairs_mut()
+        .append_pair("width", &conversion.width.to_string())
+        .append_pair("height", &conversion.height.to_string())
+        .append_pair("format", conversion.format);
+
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_small_supported_images() {
+        assert_eq!(conversion("image/png", 800, 600, 1024, 1568), None);
+        assert_eq!(conversion("image/jpeg", 1568, 1568, 1024, 1568), None);
+    }
+
+    #[test]
+    fn scales_down_large_images() {
+        assert_eq!(
+            conversion("image/png", 4000, 3000, 1024, 1568),
+            Some(Conversion {
+                width: 1568,
+                height: 1176,
+                format: "png",
+            })
+        );
+        assert_eq!(
+            conversion("image/png", 1000, 1000, MAX_IMAGE_SIZE + 1, 1568),
+            Some(Conversion {
+                width: 1000,
+                height: 1000,
+                format: "jpeg",
+            })
+        );
+    }
+
+    #[test]
+    fn converts_unsupported_formats() {
+        assert_eq!(
+            conversion("image/gif", 300, 200, 1024, 1568),
+            Some(Conversion {
+                width: 300,
+                height: 200,
+                format: "png",
+            })
+        );
+        assert_eq!(
+            conversion("image/heic", 3024, 4032, 1024, 1568),
+            Some(Conversion {
+                width: 1176,
+                height: 1568,
+                format: "jpeg",
+            })
+        );
+    }
+
+    #[test]
+    fn appends_proxy_parameters() {
+        let url = proxy_url(
+            "https://media.discordapp.net/attachments/1/2/a.png?ex=1&is=2",
+            &Conversion {
+                width: 10,
+                height: 20,
+                format: "jpeg",
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            url.as_str(),
+            "https://media.discordapp.net/attachments/1/2/a.png?ex=1&is=2&width=10&height=20&format=jpeg"
+        );
+    }
+}
@@ -0,0 +1,752 @@
+use chrono_tz::Asia::Tokyo;
+use mongodb::bson::Bson;
+use poise::serenity_prelude::{
+    self as serenity, json, ChannelId, ChannelType, GetMessages, Guild, GuildChannel, GuildId,
+    Member, MessageId, MessageUpdateEvent, PermissionOverwrite, PermissionOverwriteType,
+    Permissions, RoleId, UserId,
+};
+use poise::{CreateReply, ReplyHandle};
+use std::collections::{HashMap, HashSet};
+use std::env::var;
+use std::sync::{Arc, Mutex};
+
+use crate::db::Db;
+use crate::split::truncate;
+use crate::{Context, Error};
+
+/// Shorter messages carry too little to be worth retrieving
+const MIN_MESSAGE_LENGTH: usize = 20;
+const MAX_SNIPPET_LENGTH: usize = 300;
+const TOP_K: usize = 5;
+/// Less similar messages are not relevant enough to include
+const MIN_SIMILARITY: f32 = 0.3;
+const DEFAULT_BACKFILL_MESSAGES: u16 = 500;
+/// The most messages Discord returns at once, also embedded in one request
+const PAGE_SIZE: u8 = 100;
+
+/// Embeds the messages of selected channels with an OpenAI-compatible
+/// `/embeddings` API and finds the ones relevant to a question. The vectors
+/// are stored in MongoDB and compared in memory, which is fast enough for
+/// the size of a community server.
+pub struct Knowledge {
+    embeddings: Embeddings,
+    db: Arc<Db>,
+    /// The indexed channels and their servers, so that messages of other
+    /// channels are skipped without a query
+    channels: Mutex<HashMap<ChannelId, GuildId>>,
+    /// The embeddings of the indexed messages, so that they are not loaded
+    /// from MongoDB for every question
+    entries: Mutex<HashMap<MessageId, Entry>>,
+}
+
+/// An indexed message and its embedding
+struct Entry {
+    channel_id: ChannelId,
+    author: String,
+    content: String,
+    vector: Vec<f32>,
+}
+
+struct Embeddings {
+    http_client: Arc<reqwest::Client>,
+    api_url: String,
+    token: String,
+    model: String,
+}
+
+/// None unless `EMBEDDING_MODEL` is set
+pub async fn build_knowledge(
+    http_client: Arc<reqwest::Client>,
+    db: Arc<Db>,
+) -> Result<Option<Knowledge>, Error> {
+    let Ok(model) = var("EMBEDDING_MODEL") else {
+        return Ok(None);
+    };
+
+    let mut channels = HashMap::new();
+    for channel in db.get_indexed_channels().await? {
+        channels.insert(
+            ChannelId::new(channel.get_str("channel_id")?.parse()?),
+            GuildId::new(channel.get_str("guild_id")?.parse()?),
+        );
+    }
+
+    let mut entries = HashMap::new();
+    for document in db.get_embeddings().await? {
+        entries.insert(
+            MessageId::new(document.get_str("message_id")?.parse()?),
+            Entry {
+                channel_id: ChannelId::new(document.get_str("channel_id")?.parse()?),
+                author: document.get_str("author")?.to_owned(),
+                content: document.get_str("content")?.to_owned(),
+                vector: document
+                    .get_array("vector")?
+                    .iter()
+                    .filter_map(Bson::as_f64)
+                    .map(|value| value as f32)
+                    .collect(),
+            },
+        );
+    }
+
+    Ok(Some(Knowledge {
+        embeddings: Embeddings {
+            http_client,
+            api_url: var("EMBEDDING_API_URL")
+                .or(var("LLM_API_URL"))
+                .unwrap_or(String::from("")),
+            token: var("EMBEDDING_TOKEN")
+                .or(var("LLM_TOKEN"))
+                .unwrap_or(String::from("")),
+            model,
+        },
+        db,
+        channels: Mutex::new(channels),
+        entries: Mutex::new(entries),
+    }))
+}
+
+/// An indexed message relevant to a question
+pub struct Snippet {
+    pub author: String,
+    /// The day the message was sent in JST
+    pub date: String,
+    pub content: String,
+    pub link: String,
+}
+
+impl Knowledge {
+    /// Indexes new messages of the indexed channels
+    pub async fn on_message(&self, message: &serenity::Message) -> Result<(), Error> {
+        let Some(guild_id) = message.guild_id else {
+            return Ok(());
+        };
+        if !is_indexable(message) || !self.is_indexed(message.channel_id) {
+            return Ok(());
+        }
+
+        self.index(guild_id, std::slice::from_ref(message)).await?;
+
+        Ok(())
+    }
+
+    /// Removes deleted messages from the index
+    pub async fn on_message_delete(
+        &self,
+        channel_id: ChannelId,
+        message_ids: &[MessageId],
+    ) -> Result<(), Error> {
+        if !self.is_indexed(channel_id) {
+            return Ok(());
+        }
+
+        {
+            let mut entries = self.entries.lock().expect("entries lock poisoned");
+            for message_id in message_ids {
+                entries.remove(message_id);
+            }
+        }
+
+        let message_ids = message_ids.iter().map(|id| id.get()).collect::<Vec<_>>();
+        self.db.remove_embeddings(&message_ids).await?;
+
+        Ok(())
+    }
+
+    /// Embeds edited messages again, removing the ones no longer worth
+    /// indexing
+    pub async fn on_message_update(
+        &self,
+        ctx: &serenity::Context,
+        event: &MessageUpdateEvent,
+    ) -> Result<(), Error> {
+        let Some(guild_id) = event.guild_id else {
+            return Ok(());
+        };
+        if event.content.is_none() || !self.is_indexed(event.channel_id) {
+            return Ok(());
+        }
+
+        let message = event.channel_id.message(ctx, event.id).await?;
+        if is_indexable(&message) {
+            self.index(guild_id, std::slice::from_ref(&message)).await?;
+        } else {
+            self.entries
+                .lock()
+                .expect("entries lock poisoned")
+                .remove(&event.id);
+            self.db.remove_embeddings(&[event.id.get()]).await?;
+        }
+
+        Ok(())
+    }
+
+    /// The indexed messages most similar to `query`, out of the channels the
+    /// user can view. For a reply in `reply_channel`, also out of the ones
+    /// everyone there can view; it is None when only the user sees the reply.
+    pub async fn retrieve(
+        &self,
+        ctx: &serenity::Context,
+        guild_id: GuildId,
+        user_id: UserId,
+        reply_channel: Option<ChannelId>,
+        query: &str,
+        exclude: Option<MessageId>,
+    ) -> Result<Vec<Snippet>, Error> {
+        let indexed = self
+            .channels
+            .lock()
+            .expect("channels lock poisoned")
+            .iter()
+            .filter(|(_, guild)| **guild == guild_id)
+            .map(|(channel_id, _)| *channel_id)
+            .collect::<Vec<_>>();
+        if indexed.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let member = guild_id.member(ctx, user_id).await?;
+        let channel_ids = match ctx.cache.guild(guild_id) {
+            Some(guild) => indexed
+                .into_iter()
+                .filter(|channel_id| {
+                    can_view(&guild, &member, *channel_id)
+                        && reply_channel
+                            .is_none_or(|reply| audience_can_view(&guild, reply, *channel_id))
+                })
+                .collect::<HashSet<_>>(),
+            None => HashSet::new(),
+        };
+
+        // skip embedding the query for servers without an index
+        let has_entries = self
+            .entries
+            .lock()
+            .expect("entries lock poisoned")
+            .values()
+            .any(|entry| channel_ids.contains(&entry.channel_id));
+        if !has_entries {
+            return Ok(vec![]);
+        }
+
+        let query = self
+            .embeddings
+            .embed(&[query.to_owned()])
+            .await?
+            .pop()
+            .ok_or("no embedding for the query")?;
+
+        let entries = self.entries.lock().expect("entries lock poisoned");
+        let scored = entries
+            .iter()
+            .filter(|(message_id, entry)| {
+                channel_ids.contains(&entry.channel_id) && Some(**message_id) != exclude
+            })
+            .map(|(message_id, entry)| {
+                (
+                    cosine_similarity(&query, &entry.vector),
+                    (message_id, entry),
+                )
+            })
+            .collect();
+
+        Ok(rank(scored, TOP_K, MIN_SIMILARITY)
+            .into_iter()
+            .map(|(message_id, entry)| Snippet {
+                author: entry.author.clone(),
+                date: message_id
+                    .created_at()
+                    .with_timezone(&Tokyo)
+                    .format("%Y-%m-%d")
+                    .to_string(),
+                content: truncate(&entry.content, MAX_SNIPPET_LENGTH),
+                link: message_id.link(entry.channel_id, Some(guild_id)),
+            })
+            .collect())
+    }
+
+    pub async fn add_channel(&self, ctx: Context<'_>, messages: Option<u16>) -> Result<(), Error> {
+        let guild_id = ctx.guild_id().ok_or("Not in a server")?;
+        let limit = messages.unwrap_or(DEFAULT_BACKFILL_MESSAGES) as usize;
+
+        let handle = ctx
+            .reply("Indexing the messages of this channel...")
+            .await?;
+
+        let mut indexed = 0;
+        let result = self
+            .backfill(ctx, &handle, guild_id, limit, &mut indexed)
+            .await;
+        let reply = match &result {
+            Ok(()) => format!(
+                "Indexed {indexed} messages of this channel. New messages will be indexed as they are posted."
+            ),
+            Err(e) => format!("Indexing stopped after {indexed} messages: {e}"),
+        };
+        handle
+            .edit(ctx, CreateReply::default().content(reply))
+            .await?;
+
+        result
+    }
+
+    /// Indexes the channel and its last `limit` messages, counting the
+    /// indexed messages in `indexed` and showing the count as it grows
+    async fn backfill(
+        &self,
+        ctx: Context<'_>,
+        handle: &ReplyHandle<'_>,
+        guild_id: GuildId,
+        limit: usize,
+        indexed: &mut usize,
+    ) -> Result<(), Error> {
+        let channel_id = ctx.channel_id();
+
+        self.db
+            .add_indexed_channel(guild_id.get(), channel_id.get())
+            .await?;
+        self.channels
+            .lock()
+            .expect("channels lock poisoned")
+            .insert(channel_id, guild_id);
+
+        let mut fetched = 0;
+        let mut before = None;
+        while fetched < limit {
+            let mut request =
+                GetMessages::new().limit((limit - fetched).min(PAGE_SIZE as usize) as u8);
+            if let Some(before) = before {
+                request = request.before(before);
+            }
+            let page = channel_id.messages(ctx, request).await?;
+            let Some(last) = page.last() else {
+                break;
+            };
+            before = Some(last.id);
+            fetched += page.len();
+
+            *indexed += self.index(guild_id, &page).await?;
+            handle
+                .edit(
+                    ctx,
+                    CreateReply::default().content(format!(
+                        "Indexed {indexed} of up to {limit} messages so far..."
+                    )),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn remove_channel(&self, ctx: Context<'_>) -> Result<(), Error> {
+        let channel_id = ctx.channel_id().get();
+
+        if !self.db.remove_indexed_channel(channel_id).await? {
+            ctx.reply("This channel is not indexed.").await?;
+            return Ok(());
+        }
+        self.channels
+            .lock()
+            .expect("channels lock poisoned")
+            .remove(&ctx.channel_id());
+        self.entries
+            .lock()
+            .expect("entries lock poisoned")
+            .retain(|_, entry| entry.channel_id != ctx.channel_id());
+        let removed = self.db.remove_channel_embeddings(channel_id).await?;
+
+        ctx.reply(format!(
+            "Removed {removed} messages of this channel from the index."
+        ))
+        .await?;
+
+        Ok(())
+    }
+
+    fn is_indexed(&self, channel_id: ChannelId) -> bool {
+        self.channels
+            .lock()
+            .expect("channels lock poisoned")
+            .contains_key(&channel_id)
+    }
+
+    /// Embeds and stores the messages worth indexing, returning how many
+    /// were stored
+    async fn index(
+        &self,
+        guild_id: GuildId,
+        messages: &[serenity::Message],
+    ) -> Result<usize, Error> {
+        let messages = messages
+            .iter()
+            .filter(|message| is_indexable(message))
+            .collect::<Vec<_>>();
+        if messages.is_empty() {
+            return Ok(0);
+        }
+
+        let vectors = self
+            .embeddings
+            .embed(
+                &messages
+                    .iter()
+                    .map(|message| message.content.clone())
+                    .collect::<Vec<_>>(),
+            )
+            .await?;
+
+        for (message, vector) in messages.iter().zip(&vectors) {
+            self.db
+                .add_embedding(
+                    guild_id.get(),
+                    message.channel_id.get(),
+                    message.id.get(),
+                    message.author.display_name(),
+                    &message.content,
+                    vector,
+                )
+                .await?;
+        }
+
+        let mut entries = self.entries.lock().expect("entries lock poisoned");
+        for (message, vector) in messages.iter().zip(vectors) {
+            entries.insert(
+                message.id,
+                Entry {
+                    channel_id: message.channel_id,
+                    author: message.author.display_name().to_owned(),
+                    content: message.content.clone(),
+                    vector,
+                },
+            );
+        }
+
+        Ok(messages.len())
+    }
+}
+
+impl Embeddings {
+    async fn embed(&self, inputs: &[String]) -> Result<Vec<Vec<f32>>, Error> {
+        let body = json::json!({
+            "model": self.model,
+            "input": inputs,
+        });
+
+        let response = self
+            .http_client
+            .post(format!("{}/embeddings", self.api_url))
+            .bearer_auth(&self.token)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .await?
+            .error_for_status()?;
+        let value = json::from_str::<json::Value>(&response.text().await?)?;
+
+        let mut data = value["data"]
+            .as_array()
+            .ok_or("malformed embeddings response")?
+            .iter()
+            .map(|item| {
+                let index = item["index"].as_u64().ok_or("missing index")?;
+                let embedding = item["embedding"]
+                    .as_array()
+                    .ok_or("missing embedding")?
+                    .iter()
+                    .map(|value| value.as_f64().map(|value| value as f32))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or("malformed embedding")?;
+                Ok((index, embedding))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        if data.len() != inputs.len() {
+            return Err(format!(
+                "expected {} embeddings but got {}",
+                inputs.len(),
+                data.len()
+            )
+            .into());
+        }
+        data.sort_by_key(|(index, _)| *index);
+
+        Ok(data.into_iter().map(|(_, embedding)| embedding).collect())
+    }
+}
+
+/// Threads are viewable with their parent channel, except private ones the
+/// member may not have joined
+fn can_view(guild: &Guild, member: &Member, channel_id: ChannelId) -> bool {
+    permission_channel(guild, channel_id, false)
+        .is_some_and(|channel| guild.user_permissions_in(channel, member).view_channel())
+}
+
+/// The channel whose permissions apply, which is the parent for threads
+fn permission_channel(
+    guild: &Guild,
+    channel_id: ChannelId,
+    private_threads: bool,
+) -> Option<&GuildChannel> {
+    match guild.channels.get(&channel_id) {
+        Some(channel) => Some(channel),
+        None => guild
+            .threads
+            .iter()
+            .find(|thread| {
+                thread.id == channel_id
+                    && (private_threads || thread.kind != ChannelType::PrivateThread)
+            })
+            .and_then(|thread| thread.parent_id)
+            .and_then(|parent_id| guild.channels.get(&parent_id)),
+    }
+}
+
+/// Whether everyone who can view the reply channel can also view the source
+/// channel, so that a reply does not show messages to members who could not
+/// read them. Members given access individually are checked one by one.
+fn audience_can_view(guild: &Guild, reply_channel: ChannelId, source: ChannelId) -> bool {
+    if reply_channel == source {
+        return true;
+    }
+    // a private thread has fewer viewers than its parent
+    let (Some(reply_channel), Some(source)) = (
+        permission_channel(guild, reply_channel, true),
+        permission_channel(guild, source, false),
+    ) else {
+        return false;
+    };
+
+    let roles = guild
+        .roles
+        .iter()
+        .map(|(role_id, role)| (*role_id, role.permissions))
+        .collect::<Vec<_>>();
+    let members_can_view =
+        reply_channel
+            .permission_overwrites
+            .iter()
+            .all(|overwrite| match overwrite.kind {
+                PermissionOverwriteType::Member(user_id) if overwrite.allow.view_channel() => guild
+                    .members
+                    .get(&user_id)
+                    .is_some_and(|member| guild.user_permissions_in(source, member).view_channel()),
+                _ => true,
+            });
+
+    members_can_view
+        && roles_can_view(
+            &roles,
+            guild.id.everyone_role(),
+            &reply_channel.permission_overwrites,
+            &source.permission_overwrites,
+        )
+}
+
+/// Whether each role able to view the reply channel can view the source
+/// channel, given their permission overwrites
+fn roles_can_view(
+    roles: &[(RoleId, Permissions)],
+    everyone: RoleId,
+    reply_channel: &[PermissionOverwrite],
+    source: &[PermissionOverwrite],
+) -> bool {
+    roles.iter().all(|(role_id, _)| {
+        !role_can_view(roles, everyone, *role_id, reply_channel)
+            || role_can_view(roles, everyone, *role_id, source)
+    })
+}
+
+/// As if a member had only this role besides @everyone
+fn role_can_view(
+    roles: &[(RoleId, Permissions)],
+    everyone: RoleId,
+    role_id: RoleId,
+    overwrites: &[PermissionOverwrite],
+) -> bool {
+    let mut permissions = roles
+        .iter()
+        .filter(|(id, _)| *id == everyone || *id == role_id)
+        .fold(
+            Permissions::empty(),
+            |permissions, (_, role_permissions)| permissions | *role_permissions,
+        );
+    if permissions.administrator() {
+        return true;
+    }
+
+    // the @everyone overwrite applies before the one of the role
+    for id in [everyone, role_id] {
+        if let Some(overwrite) = overwrites
+            .iter()
+            .find(|overwrite| overwrite.kind == PermissionOverwriteType::Role(id))
+        {
+            permissions = (permissions & !overwrite.deny) | overwrite.allow;
+        }
+    }
+
+    permissions.view_channel()
+}
+
+fn is_indexable(message: &serenity::Message) -> bool {
+    !message.author.bot && message.content.trim().chars().count() >= MIN_MESSAGE_LENGTH
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+
+    let dot = a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+    let norm =
+        a.iter().map(|a| a * a).sum::<f32>().sqrt() * b.iter().map(|b| b * b).sum::<f32>().sqrt();
+    if norm == 0.0 {
+        0.0
+    } else {
+        dot / norm
+    }
+}
+
+/// The `k` most similar items above `min_similarity`, most similar first
+fn rank<T>(mut scored: Vec<(f32, T)>, k: usize, min_similarity: f32) -> Vec<T> {
+    scored.retain(|(similarity, _)| *similarity >= min_similarity);
+    scored.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+
+    scored.into_iter().take(k).map(|(_, item)| item).collect()
+}
+
+/// Let the assistant search past messages of this channel
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+    subcommands("knowledge_add", "knowledge_remove"),
+    subcommand_required
+)]
+pub async fn knowledge(_ctx: Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Index this channel, starting with its recent messages
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+    rename = "add"
+)]
+pub async fn knowledge_add(
+    ctx: Context<'_>,
+    #[description = "Number of past messages to index (default: 500)"]
+    #[max = 5000]
+    messages: Option<u16>,
+) -> Result<(), Error> {
+    match &ctx.data().knowledge {
+        Some(knowledge) => knowledge.add_channel(ctx, messages).await,
+        None => {
+            ctx.reply("Set EMBEDDING_MODEL to enable the index.")
+                .await?;
+            Ok(())
+        }
+    }
+}
+
+/// Stop indexing this channel and delete its messages from the index
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+    rename = "remove"
+)]
+pub async fn knowledge_remove(ctx: Context<'_>) -> Result<(), Error> {
+    match &ctx.data().knowledge {
+        Some(knowledge) => knowledge.remove_channel(ctx).await,
+        None => {
+            ctx.reply("Set EMBEDDING_MODEL to enable the index.")
+                .await?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::mock::{request_body, serve};
+
+    #[test]
+    fn compares_vectors() {
+        assert!((cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+        assert_eq!(cosine_similarity(&[1.0], &[1.0, 0.0]), 0.0);
+    }
+
+    #[test]
+    fn keeps_private_channels_out_of_public_replies() {
+        let everyone = RoleId::new(1);
+        let moderator = RoleId::new(2);
+        let administrator = RoleId::new(3);
+        let roles = [
+            (everyone, Permissions::VIEW_CHANNEL),
+            (moderator, Permissions::empty()),
+            (administrator, Permissions::ADMINISTRATOR),
+        ];
+        let public = [];
+        let moderators_only = [
+            PermissionOverwrite {
+                allow: Permissions::empty(),
+                deny: Permissions::VIEW_CHANNEL,
+                kind: PermissionOverwriteType::Role(everyone),
+            },
+            PermissionOverwrite {
+                allow: Permissions::VIEW_CHANNEL,
+                deny: Permissions::empty(),
+                kind: PermissionOverwriteType::Role(moderator),
+            },
+        ];
+
+        assert!(roles_can_view(&roles, everyone, &public, &public));
+        assert!(roles_can_view(&roles, everyone, &moderators_only, &public));
+        assert!(roles_can_view(
+            &roles,
+            everyone,
+            &moderators_only,
+            &moderators_only
+        ));
+        assert!(!roles_can_view(&roles, everyone, &public, &moderators_only));
+    }
+
+    #[test]
+    fn ranks_by_similarity() {
+        let scored = vec![(0.5, "a"), (0.9, "b"), (0.1, "c"), (0.7, "d")];
+
+        assert_eq!(rank(scored, 2, 0.3), vec!["b", "d"]);
+    }
+
+    #[tokio::test]
+    async fn embeds_in_input_order() {
+        let (url, request) = serve(
+            "application/json",
+            r#"{"data":[{"index":1,"embedding":[0.0,1.0]},{"index":0,"embedding":[1.0,0.0]}]}"#,
+        )
+        .await;
+
+        let embeddings = Embeddings {
+            http_client: Arc::new(reqwest::Client::new()),
+            api_url: url,
+            token: "token".to_owned(),
+            model: "embed".to_owned(),
+        };
+
+        let vectors = embeddings
+            .embed(&["first".to_owned(), "second".to_owned()])
+            .await
+            .unwrap();
+
+        assert_eq!(vectors, vec![vec![1.0, 0.0], vec![0.0, 1.0]]);
+
+        let body = request_body(&request.await.unwrap());
+        assert_eq!(body["model"], "embed");
+        assert_eq!(body["input"][1], "second");
+    }
+}
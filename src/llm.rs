@@ -0,0 +1,493 @@
+mod anthropic;
+mod ollama;
+mod openai;
+
+use futures::stream::{self, BoxStream, StreamExt};
+use poise::serenity_prelude::json;
+use std::collections::VecDeque;
+use std::env::var;
+use std::sync::Arc;
+
+use crate::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+    Tool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Part {
+    Text(String),
+    /// Base64 encoded image
+    Image {
+        media_type: String,
+        data: String,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: Vec<Part>,
+    /// Tool calls requested by the assistant
+    pub tool_calls: Vec<ToolCall>,
+    /// The call a tool message is the result of
+    pub tool_call_id: Option<String>,
+}
+
+impl ChatMessage {
+    pub fn new(role: Role, content: Vec<Part>) -> ChatMessage {
+        ChatMessage {
+            role,
+            content,
+            tool_calls: vec![],
+            tool_call_id: None,
+        }
+    }
+
+    pub fn text(role: Role, text: impl Into<String>) -> ChatMessage {
+        ChatMessage::new(role, vec![Part::Text(text.into())])
+    }
+
+    pub fn tool_calls(tool_calls: Vec<ToolCall>) -> ChatMessage {
+        ChatMessage {
+            tool_calls,
+            ..ChatMessage::new(Role::Assistant, vec![])
+        }
+    }
+
+    pub fn tool_result(tool_call_id: String, result: String) -> ChatMessage {
+        ChatMessage {
+            tool_call_id: Some(tool_call_id),
+            ..ChatMessage::text(Role::Tool, result)
+        }
+    }
+
+    /// The text parts joined together
+    pub fn joined_text(&self) -> String {
+        self.content
+            .iter()
+            .filter_map(|part| match part {
+                Part::Text(text) => Some(text.as_str()),
+                Part::Image { .. } => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    /// Arguments as a JSON string
+    pub arguments: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tool {
+    pub name: String,
+    pub description: String,
+    /// JSON schema of the arguments
+    pub parameters: json::Value,
+}
+
+/// A failure of the completion API outside the stream
+#[derive(Debug, PartialEq)]
+pub enum ProviderError {
+    /// The API responded with a non-2xx status
+    Http {
+        status: u16,
+        message: String,
+        /// Seconds from the Retry-After header
+        retry_after: Option<u64>,
+    },
+    /// The completion contained neither text nor tool calls
+    Empty,
+}
+
+impl std::fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProviderError::Http {
+                status, message, ..
+            } => write!(f, "HTTP {status}: {message}"),
+            ProviderError::Empty => write!(f, "empty completion"),
+        }
+    }
+}
+
+impl std::error::Error for ProviderError {}
+
+/// Tokens billed for a completion
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl Usage {
+    /// For providers not reporting usage
+    pub fn estimate(prompt: &[ChatMessage], completion: &ChatMessage) -> Usage {
+        Usage {
+            prompt_tokens: prompt.iter().map(estimate_tokens).sum::<usize>() as u64,
+            completion_tokens: estimate_tokens(completion) as u64,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+impl std::ops::AddAssign for Usage {
+    fn add_assign(&mut self, other: Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
+}
+
+pub struct CompletionRequest<'a> {
+    pub model: &'a str,
+    pub messages: &'a [ChatMessage],
+    pub tools: &'a [Tool],
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StreamEvent {
+    Text(String),
+    ToolCall(ToolCall),
+    /// Normalized to "stop", "length" or "tool_calls"
+    Finish(String),
+    Usage(Usage),
+}
+
+pub type EventStream = BoxStream<'static, Result<StreamEvent, Error>>;
+
+/// A chat completion API
+#[async_trait::async_trait]
+pub trait Provider: Send + Sync {
+    async fn stream(&self, request: &CompletionRequest<'_>) -> Result<EventStream, Error>;
+}
+
+/// Selected by `LLM_PROVIDER`: "openai" (default), "anthropic" or "ollama"
+pub fn build_provider(http_client: Arc<reqwest::Client>) -> Result<Box<dyn Provider>, Error> {
+    let api_url = var("LLM_API_URL").unwrap_or(String::from(""));
+    let token = var("LLM_TOKEN").unwrap_or(String::from(""));
+
+    match var("LLM_PROVIDER").as_deref() {
+        Ok("openai") | Err(_) => Ok(Box::new(openai::OpenAi {
+            http_client,
+            api_url,
+            token,
+            stream_usage: var("LLM_STREAM_USAGE").map_or(true, |usage| usage != "false"),
+        })),
+        Ok("anthropic") => Ok(Box::new(anthropic::Anthropic {
+            http_client,
+            api_url,
+            token,
+            max_tokens: match var("LLM_MAX_TOKENS") {
+                Ok(max_tokens) => max_tokens.parse()?,
+                Err(_) => anthropic::DEFAULT_MAX_TOKENS,
+            },
+        })),
+        Ok("ollama") => Ok(Box::new(ollama::Ollama {
+            http_client,
+            api_url,
+        })),
+        Ok(provider) => Err(format!("Unknown LLM_PROVIDER {provider}").into()),
+    }
+}
+
+/// A rough estimate erring on the high side for Japanese text, counting
+/// four ASCII characters or one other character as a token
+pub fn estimate_tokens(message: &ChatMessage) -> usize {
+    let text = message.joined_text()
+        + &message
+            .tool_calls
+            .iter()
+            .map(|tool_call| format!("{}{}", tool_call.name, tool_call.arguments))
+            .collect::<String>();
+    let ascii = text.chars().filter(char::is_ascii).count();
+    let images = message
+        .content
+        .iter()
+        .filter(|part| matches!(part, Part::Image { .. }))
+        .count();
+
+    ascii.div_ceil(4) + (text.chars().count() - ascii) + images * IMAGE_TOKENS
+}
+
+pub const IMAGE_TOKENS: usize = 1000;
+
+/// Drops the oldest messages after the system prompt until the conversation
+/// fits in the budget and returns them. The latest message is always kept.
+pub fn trim_to_budget(messages: &mut Vec<ChatMessage>, budget: usize) -> Vec<ChatMessage> {
+    let start = messages
+        .iter()
+        .position(|message| message.role != Role::System)
+        .unwrap_or(messages.len());
+    let mut dropped = vec![];
+
+    while messages.len() > start + 1 && messages.iter().map(estimate_tokens).sum::<usize>() > budget
+    {
+        dropped.push(messages.remove(start));
+        // a tool result is meaningless without the call it answers
+        while messages.len() > start + 1 && messages[start].role == Role::Tool {
+            dropped.push(messages.remove(start));
+        }
+    }
+
+    dropped
+}
+
+/// Runs a completion without tools and collects its text
+pub async fn complete_text(
+    provider: &dyn Provider,
+    request: &CompletionRequest<'_>,
+) -> Result<(String, Usage), Error> {
+    let mut stream = provider.stream(request).await?;
+    let mut text = String::new();
+    let mut usage = None;
+
+    while let Some(event) = stream.next().await {
+        match event? {
+            StreamEvent::Text(delta) => text.push_str(&delta),
+            StreamEvent::Usage(reported) => usage = Some(reported),
+            _ => {}
+        }
+    }
+
+    let usage = usage.unwrap_or_else(|| {
+        Usage::estimate(
+            request.messages,
+            &ChatMessage::text(Role::Assistant, text.as_str()),
+        )
+    });
+
+    Ok((text, usage))
+}
+
+/// Incremental decoder of a provider's streamed response body
+trait StreamDecoder: Send + 'static {
+    fn feed(&mut self, bytes: &[u8]) -> Vec<Result<StreamEvent, Error>>;
+    fn finish(&mut self) -> Vec<Result<StreamEvent, Error>>;
+}
+
+/// A non-2xx response is turned into an error with the message of its body
+async fn decode_stream(
+    response: reqwest::Response,
+    decoder: impl StreamDecoder,
+) -> Result<EventStream, Error> {
+    let status = response.status();
+    if !status.is_success() {
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|retry_after| retry_after.to_str().ok())
+            .and_then(|retry_after| retry_after.parse().ok());
+        let body = response.text().await.unwrap_or_default();
+        let message = json::from_str::<json::Value>(&body)
+            .ok()
+            .and_then(|value| {
+                value["error"]["message"]
+                    .as_str()
+                    .or(value["error"].as_str())
+                    .map(str::to_owned)
+            })
+            .unwrap_or(body);
+
+        return Err(ProviderError::Http {
+            status: status.as_u16(),
+            message,
+            retry_after,
+        }
+        .into());
+    }
+
+    struct State<D> {
+        body: BoxStream<'static, reqwest::Result<Vec<u8>>>,
+        decoder: D,
+        pending: VecDeque<Result<StreamEvent, Error>>,
+        ended: bool,
+    }
+
+    let state = State {
+        body: response
+            .bytes_stream()
+            .map(|bytes| bytes.map(|bytes| bytes.to_vec()))
+            .boxed(),
+        decoder,
+        pending: VecDeque::new(),
+        ended: false,
+    };
+
+    Ok(stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(event) = state.pending.pop_front() {
+                return Some((event, state));
+            }
+            if state.ended {
+                return None;
+            }
+
+            match state.body.next().await {
+                Some(Ok(bytes)) => state.pending.extend(state.decoder.feed(&bytes)),
+                Some(Err(e)) => {
+                    state.ended = true;
+                    state.pending.push_back(Err(e.into()));
+                }
+                None => {
+                    state.ended = true;
+                    state.pending.extend(state.decoder.finish());
+                }
+            }
+        }
+    })
+    .boxed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_tokens_of_text_and_images() {
+        assert_eq!(
+            estimate_tokens(&ChatMessage::text(Role::User, "abcdefgh")),
+            2
+        );
+        assert_eq!(
+            estimate_tokens(&ChatMessage::text(Role::User, "こんにちは")),
+            5
+        );
+
+        let message = ChatMessage::new(
+            Role::User,
+            vec![
+                Part::Text("abc".to_owned()),
+                Part::Image {
+                    media_type: "image/png".to_owned(),
+                    data: String::new(),
+                },
+            ],
+        );
+        assert_eq!(estimate_tokens(&message), 1 + IMAGE_TOKENS);
+    }
+
+    #[test]
+    fn trims_oldest_messages_after_the_system_prompt() {
+        let mut messages = vec![
+            ChatMessage::text(Role::System, "ab"),
+            ChatMessage::text(Role::User, "あいう"),
+            ChatMessage::tool_calls(vec![ToolCall {
+                id: "1".to_owned(),
+                name: "abc".to_owned(),
+                arguments: "{}".to_owned(),
+            }]),
+            ChatMessage::tool_result("1".to_owned(), "あいう".to_owned()),
+            ChatMessage::text(Role::Assistant, "あい"),
+            ChatMessage::text(Role::User, "あ"),
+        ];
+
+        let dropped = trim_to_budget(&mut messages, 5);
+
+        assert_eq!(dropped.len(), 3);
+        assert_eq!(dropped[2].role, Role::Tool);
+        assert_eq!(
+            messages,
+            vec![
+                ChatMessage::text(Role::System, "ab"),
+                ChatMessage::text(Role::Assistant, "あい"),
+                ChatMessage::text(Role::User, "あ"),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_the_latest_message_over_budget() {
+        let mut messages = vec![
+            ChatMessage::text(Role::User, "あいう"),
+            ChatMessage::text(Role::User, "あいうえお"),
+        ];
+
+        trim_to_budget(&mut messages, 1);
+
+        assert_eq!(messages, vec![ChatMessage::text(Role::User, "あいうえお")]);
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    /// Serves a single HTTP response and returns the base URL together with a
+    /// handle resolving to the raw request that was received
+    pub async fn serve(content_type: &str, body: &str) -> (String, JoinHandle<String>) {
+        serve_response("200 OK", &format!("Content-Type: {content_type}\r\n"), body).await
+    }
+
+    /// `headers` are raw header lines, each ending with CRLF
+    pub async fn serve_response(
+        status: &str,
+        headers: &str,
+        body: &str,
+    ) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let url = format!("http://{}", listener.local_addr().expect("no address"));
+        let response = format!(
+            "HTTP/1.1 {status}\r\n{headers}Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.expect("failed to accept");
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+
+            loop {
+                let n = socket.read(&mut buffer).await.expect("failed to read");
+                request.extend_from_slice(&buffer[..n]);
+
+                let text = String::from_utf8_lossy(&request);
+                if let Some((headers, body)) = text.split_once("\r\n\r\n") {
+                    let content_length = headers
+                        .lines()
+                        .find_map(|line| {
+                            line.to_ascii_lowercase()
+                                .strip_prefix("content-length:")
+                                .map(|length| length.trim().parse::<usize>().unwrap_or(0))
+                        })
+                        .unwrap_or(0);
+                    if body.len() >= content_length {
+                        break;
+                    }
+                }
+                if n == 0 {
+                    break;
+                }
+            }
+
+            socket
+                .write_all(response.as_bytes())
+                .await
+                .expect("failed to write");
+
+            String::from_utf8_lossy(&request).into_owned()
+        });
+
+        (url, handle)
+    }
+
+    pub fn request_body(request: &str) -> poise::serenity_prelude::json::Value {
+        let (_, body) = request.split_once("\r\n\r\n").expect("no body");
+        poise::serenity_prelude::json::from_str(body).expect("body is not JSON")
+    }
+}
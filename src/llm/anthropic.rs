@@ -0,0 +1,422 @@
+use poise::serenity_prelude::json;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use super::{
+    decode_stream, ChatMessage, CompletionRequest, EventStream, Part, Provider, Role,
+    StreamDecoder, StreamEvent, ToolCall, Usage,
+};
+use crate::sse::{self, CompletionError};
+use crate::Error;
+
+pub const DEFAULT_MAX_TOKENS: u32 = 4096;
+const API_VERSION: &str = "2023-06-01";
+
+/// Anthropic Messages API
+pub struct Anthropic {
+    pub http_client: Arc<reqwest::Client>,
+    pub api_url: String,
+    pub token: String,
+    pub max_tokens: u32,
+}
+
+#[async_trait::async_trait]
+impl Provider for Anthropic {
+    async fn stream(&self, request: &CompletionRequest<'_>) -> Result<EventStream, Error> {
+        let system = request
+            .messages
+            .iter()
+            .filter(|message| message.role == Role::System)
+            .map(ChatMessage::joined_text)
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let mut body = json::json!({
+            "model": request.model,
+            "max_tokens": self.max_tokens,
+            "messages": encode_messages(request.messages),
+            "stream": true,
+        });
+        if !system.is_empty() {
+            body["system"] = json::json!(system);
+        }
+        if !request.tools.is_empty() {
+            body["tools"] = request
+                .tools
+                .iter()
+                .map(|tool| {
+                    json::json!({
+                        "name": tool.name,
+                        "description": tool.description,
+                        "input_schema": tool.parameters,
+                    })
+                })
+                .collect();
+        }
+
+        let response = self
+            .http_client
+            .post(format!("{}/messages", self.api_url))
+            .header("x-api-key", &self.token)
+            .header("anthropic-version", API_VERSION)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .await?;
+
+        decode_stream(response, Decoder::default()).await
+    }
+}
+
+/// Consecutive messages of the same role are merged, since the API requires
+/// alternating roles and all tool results in a single user message. Empty
+/// messages are dropped and the conversation starts with a user message.
+fn encode_messages(messages: &[ChatMessage]) -> Vec<json::Value> {
+    let mut encoded: Vec<(&str, Vec<json::Value>)> = vec![];
+
+    for message in messages {
+        let (role, blocks) = match message.role {
+            Role::System => continue,
+            Role::User | Role::Assistant => {
+                let role = if message.role == Role::User {
+                    "user"
+                } else {
+                    "assistant"
+                };
+                let mut blocks = message
+                    .content
+                    .iter()
+                    .filter_map(|part| match part {
+                        Part::Text(text) if text.is_empty() => None,
+                        Part::Text(text) => Some(json::json!({
+                            "type": "text",
+                            "text": text,
+                        })),
+                        Part::Image { media_type, data } => Some(json::json!({
+                            "type": "image",
+                            "source": {
+                                "type": "base64",
+                                "media_type": media_type,
+                                "data": data,
+                            },
+                        })),
+                    })
+                    .collect::<Vec<_>>();
+                blocks.extend(message.tool_calls.iter().map(|tool_call| {
+                    json::json!({
+                        "type": "tool_use",
+                        "id": tool_call.id,
+                        "name": tool_call.name,
+                        "input": json::from_str::<json::Value>(&tool_call.arguments)
+                            .unwrap_or(json::json!({})),
+                    })
+                }));
+
+                (role, blocks)
+            }
+            Role::Tool => (
+                "user",
+                vec![json::json!({
+                    "type": "tool_result",
+                    "tool_use_id": message.tool_call_id,
+                    "content": message.joined_text(),
+                })],
+            ),
+        };
+
+        if blocks.is_empty() {
+            continue;
+        }
+        match encoded.last_mut() {
+            Some((last_role, last_blocks)) if *last_role == role => last_blocks.extend(blocks),
+            _ => encoded.push((role, blocks)),
+        }
+    }
+
+    // trimming may leave an assistant turn, or results of its tool calls, first
+    loop {
+        match encoded.first_mut() {
+            Some(("assistant", _)) => {
+                encoded.remove(0);
+            }
+            Some((_, blocks)) => {
+                blocks.retain(|block| block["type"] != "tool_result");
+                if !blocks.is_empty() {
+                    break;
+                }
+                encoded.remove(0);
+            }
+            None => break,
+        }
+    }
+
+    encoded
+        .into_iter()
+        .map(|(role, blocks)| json::json!({ "role": role, "content": blocks }))
+        .collect()
+}
+
+#[derive(Default)]
+struct Decoder {
+    sse: sse::Decoder,
+    /// tool_use blocks by content block index
+    tool_calls: BTreeMap<u64, ToolCall>,
+    /// Reported by message_start, while output tokens come with message_delta
+    input_tokens: u64,
+}
+
+impl Decoder {
+    fn on_event(&mut self, event: sse::Event, events: &mut Vec<Result<StreamEvent, Error>>) {
+        let value: json::Value = match json::from_str(&event.data) {
+            Ok(value) => value,
+            Err(_) => {
+                events.push(Err(CompletionError::Malformed(event.data).into()));
+                return;
+            }
+        };
+        let index = value["index"].as_u64().unwrap_or(0);
+
+        match value["type"].as_str().unwrap_or("") {
+            "message_start" => {
+                self.input_tokens = value["message"]["usage"]["input_tokens"]
+                    .as_u64()
+                    .unwrap_or(0);
+            }
+            "content_block_start" if value["content_block"]["type"] == "tool_use" => {
+                let block = &value["content_block"];
+                self.tool_calls.insert(
+                    index,
+                    ToolCall {
+                        id: block["id"].as_str().unwrap_or("").to_owned(),
+                        name: block["name"].as_str().unwrap_or("").to_owned(),
+                        arguments: String::new(),
+                    },
+                );
+            }
+            "content_block_delta" => {
+                let delta = &value["delta"];
+                match delta["type"].as_str().unwrap_or("") {
+                    "text_delta" => {
+                        if let Some(text) = delta["text"].as_str() {
+                            events.push(Ok(StreamEvent::Text(text.to_owned())));
+                        }
+                    }
+                    "input_json_delta" => {
+                        if let Some(tool_call) = self.tool_calls.get_mut(&index) {
+                            tool_call
+                                .arguments
+                                .push_str(delta["partial_json"].as_str().unwrap_or(""));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            "content_block_stop" => {
+                if let Some(mut tool_call) = self.tool_calls.remove(&index) {
+                    if tool_call.arguments.is_empty() {
+                        tool_call.arguments = String::from("{}");
+                    }
+                    events.push(Ok(StreamEvent::ToolCall(tool_call)));
+                }
+            }
+            "message_delta" => {
+                if let Some(output_tokens) = value["usage"]["output_tokens"].as_u64() {
+                    events.push(Ok(StreamEvent::Usage(Usage {
+                        prompt_tokens: self.input_tokens,
+                        completion_tokens: output_tokens,
+                    })));
+                }
+                if let Some(stop_reason) = value["delta"]["stop_reason"].as_str() {
+                    let finish_reason = match stop_reason {
+                        "max_tokens" => "length",
+                        "tool_use" => "tool_calls",
+                        _ => "stop",
+                    };
+                    events.push(Ok(StreamEvent::Finish(finish_reason.to_owned())));
+                }
+            }
+            "error" => {
+                let message = value["error"]["message"]
+                    .as_str()
+                    .map(str::to_owned)
+                    .unwrap_or_else(|| value["error"].to_string());
+                events.push(Err(CompletionError::Api(message).into()));
+            }
+            _ => {}
+        }
+    }
+}
+
+impl StreamDecoder for Decoder {
+    fn feed(&mut self, bytes: &[u8]) -> Vec<Result<StreamEvent, Error>> {
+        let mut events = vec![];
+        for event in self.sse.feed(bytes) {
+            self.on_event(event, &mut events);
+        }
+
+        events
+    }
+
+    fn finish(&mut self) -> Vec<Result<StreamEvent, Error>> {
+        let mut events = vec![];
+        if let Some(event) = self.sse.finish() {
+            self.on_event(event, &mut events);
+        }
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::mock::{request_body, serve};
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn streams_text_and_tool_use_from_a_mock_server() {
+        let body = concat!(
+            "event: message_start\n",
+            "data: {\"type\":\"message_start\",\"message\":{\"usage\":{\"input_tokens\":10}}}\n\n",
+            "event: content_block_start\n",
+            "data: {\"type\":\"content_block_start\",\"index\":0,\"content_block\":{\"type\":\"text\",\"text\":\"\"}}\n\n",
+            "event: content_block_delta\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"Hi\"}}\n\n",
+            "event: content_block_stop\n",
+            "data: {\"type\":\"content_block_stop\",\"index\":0}\n\n",
+            "event: content_block_start\n",
+            "data: {\"type\":\"content_block_start\",\"index\":1,\"content_block\":{\"type\":\"tool_use\",\"id\":\"toolu_1\",\"name\":\"list_reminders\",\"input\":{}}}\n\n",
+            "event: content_block_delta\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":1,\"delta\":{\"type\":\"input_json_delta\",\"partial_json\":\"{}\"}}\n\n",
+            "event: content_block_stop\n",
+            "data: {\"type\":\"content_block_stop\",\"index\":1}\n\n",
+            "event: message_delta\n",
+            "data: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"tool_use\"},\"usage\":{\"output_tokens\":5}}\n\n",
+            "event: message_stop\n",
+            "data: {\"type\":\"message_stop\"}\n\n",
+        );
+        let (url, request) = serve("text/event-stream", body).await;
+
+        let provider = Anthropic {
+            http_client: Arc::new(reqwest::Client::new()),
+            api_url: url,
+            token: "key".to_owned(),
+            max_tokens: 100,
+        };
+        let messages = [
+            ChatMessage::text(Role::System, "system"),
+            ChatMessage::new(
+                Role::User,
+                vec![
+                    Part::Text("hi".to_owned()),
+                    Part::Image {
+                        media_type: "image/png".to_owned(),
+                        data: "AAAA".to_owned(),
+                    },
+                ],
+            ),
+            ChatMessage::tool_calls(vec![ToolCall {
+                id: "toolu_0".to_owned(),
+                name: "list_speakers".to_owned(),
+                arguments: "{}".to_owned(),
+            }]),
+            ChatMessage::tool_result("toolu_0".to_owned(), "[]".to_owned()),
+        ];
+
+        let events = provider
+            .stream(&CompletionRequest {
+                model: "claude",
+                messages: &messages,
+                tools: &[],
+            })
+            .await
+            .expect("request failed")
+            .map(|event| event.expect("stream failed"))
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(
+            events,
+            vec![
+                StreamEvent::Text("Hi".to_owned()),
+                StreamEvent::ToolCall(ToolCall {
+                    id: "toolu_1".to_owned(),
+                    name: "list_reminders".to_owned(),
+                    arguments: "{}".to_owned(),
+                }),
+                StreamEvent::Usage(Usage {
+                    prompt_tokens: 10,
+                    completion_tokens: 5,
+                }),
+                StreamEvent::Finish("tool_calls".to_owned()),
+            ]
+        );
+
+        let request = request.await.expect("server failed");
+        assert!(request.starts_with("POST /messages"));
+        assert!(request.contains("x-api-key: key"));
+
+        let body = request_body(&request);
+        assert_eq!(body["system"], "system");
+        assert_eq!(body["max_tokens"], 100);
+        assert_eq!(body["messages"][0]["content"][1]["source"]["data"], "AAAA");
+        assert_eq!(body["messages"][1]["content"][0]["type"], "tool_use");
+        assert_eq!(body["messages"][2]["content"][0]["tool_use_id"], "toolu_0");
+    }
+
+    #[test]
+    fn starts_with_a_user_message() {
+        let messages = [
+            ChatMessage::text(Role::System, "system"),
+            ChatMessage::tool_calls(vec![ToolCall {
+                id: "toolu_0".to_owned(),
+                name: "list_speakers".to_owned(),
+                arguments: "{}".to_owned(),
+            }]),
+            ChatMessage::tool_result("toolu_0".to_owned(), "[]".to_owned()),
+            ChatMessage::text(Role::Assistant, "No speakers."),
+            ChatMessage::text(Role::User, ""),
+            ChatMessage::text(Role::User, "hi"),
+        ];
+
+        assert_eq!(
+            encode_messages(&messages),
+            vec![json::json!({
+                "role": "user",
+                "content": [{ "type": "text", "text": "hi" }],
+            })]
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_stream_errors() {
+        let body =
+            "event: error\ndata: {\"type\":\"error\",\"error\":{\"message\":\"Overloaded\"}}\n\n";
+        let (url, _request) = serve("text/event-stream", body).await;
+
+        let provider = Anthropic {
+            http_client: Arc::new(reqwest::Client::new()),
+            api_url: url,
+            token: "key".to_owned(),
+            max_tokens: 100,
+        };
+        let messages = [ChatMessage::text(Role::User, "hi")];
+
+        let events = provider
+            .stream(&CompletionRequest {
+                model: "claude",
+                messages: &messages,
+                tools: &[],
+            })
+            .await
+            .expect("request failed")
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].as_ref().map_err(|e| e.to_string()).unwrap_err(),
+            "API error: Overloaded"
+        );
+    }
+}
@@ -0,0 +1,274 @@
+use poise::serenity_prelude::json;
+use std::sync::Arc;
+
+use super::{
+    decode_stream, ChatMessage, CompletionRequest, EventStream, Part, Provider, Role,
+    StreamDecoder, StreamEvent, ToolCall, Usage,
+};
+use crate::sse::CompletionError;
+use crate::Error;
+
+/// Ollama's native `/api/chat` API
+pub struct Ollama {
+    pub http_client: Arc<reqwest::Client>,
+    pub api_url: String,
+}
+
+#[async_trait::async_trait]
+impl Provider for Ollama {
+    async fn stream(&self, request: &CompletionRequest<'_>) -> Result<EventStream, Error> {
+        let mut body = json::json!({
+            "model": request.model,
+            "messages": request.messages.iter().map(encode_message).collect::<Vec<_>>(),
+            "stream": true,
+        });
+        if !request.tools.is_empty() {
+            body["tools"] = request
+                .tools
+                .iter()
+                .map(|tool| {
+                    json::json!({
+                        "type": "function",
+                        "function": {
+                            "name": tool.name,
+                            "description": tool.description,
+                            "parameters": tool.parameters,
+                        },
+                    })
+                })
+                .collect();
+        }
+
+        let response = self
+            .http_client
+            .post(format!("{}/api/chat", self.api_url))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .await?;
+
+        decode_stream(response, Decoder::default()).await
+    }
+}
+
+fn encode_message(message: &ChatMessage) -> json::Value {
+    let role = match message.role {
+        Role::System => "system",
+        Role::User => "user",
+        Role::Assistant => "assistant",
+        Role::Tool => "tool",
+    };
+    let images = message
+        .content
+        .iter()
+        .filter_map(|part| match part {
+            Part::Image { data, .. } => Some(data.as_str()),
+            Part::Text(_) => None,
+        })
+        .collect::<Vec<_>>();
+
+    let mut encoded = json::json!({
+        "role": role,
+        "content": message.joined_text(),
+    });
+    if !images.is_empty() {
+        encoded["images"] = json::json!(images);
+    }
+    if !message.tool_calls.is_empty() {
+        encoded["tool_calls"] = message
+            .tool_calls
+            .iter()
+            .map(|tool_call| {
+                json::json!({
+                    "function": {
+                        "name": tool_call.name,
+                        "arguments": json::from_str::<json::Value>(&tool_call.arguments)
+                            .unwrap_or(json::json!({})),
+                    },
+                })
+            })
+            .collect();
+    }
+
+    encoded
+}
+
+/// Decodes newline delimited JSON
+#[derive(Default)]
+struct Decoder {
+    buffer: Vec<u8>,
+    tool_calls: usize,
+}
+
+impl Decoder {
+    fn on_line(&mut self, line: &[u8], events: &mut Vec<Result<StreamEvent, Error>>) {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim();
+        if line.is_empty() {
+            return;
+        }
+
+        let value: json::Value = match json::from_str(line) {
+            Ok(value) => value,
+            Err(_) => {
+                events.push(Err(CompletionError::Malformed(line.to_owned()).into()));
+                return;
+            }
+        };
+
+        if let Some(error) = value["error"].as_str() {
+            events.push(Err(CompletionError::Api(error.to_owned()).into()));
+            return;
+        }
+
+        let message = &value["message"];
+        if let Some(content) = message["content"].as_str().filter(|c| !c.is_empty()) {
+            events.push(Ok(StreamEvent::Text(content.to_owned())));
+        }
+
+        for tool_call in message["tool_calls"].as_array().into_iter().flatten() {
+            // Ollama does not assign ids to tool calls
+            self.tool_calls += 1;
+            events.push(Ok(StreamEvent::ToolCall(ToolCall {
+                id: format!("call_{}", self.tool_calls),
+                name: tool_call["function"]["name"]
+                    .as_str()
+                    .unwrap_or("")
+                    .to_owned(),
+                arguments: tool_call["function"]["arguments"].to_string(),
+            })));
+        }
+
+        if value["done"].as_bool().unwrap_or(false) {
+            if let Some(prompt_tokens) = value["prompt_eval_count"].as_u64() {
+                events.push(Ok(StreamEvent::Usage(Usage {
+                    prompt_tokens,
+                    completion_tokens: value["eval_count"].as_u64().unwrap_or(0),
+                })));
+            }
+            let finish_reason = if self.tool_calls > 0 {
+                "tool_calls"
+            } else if value["done_reason"] == "length" {
+                "length"
+            } else {
+                "stop"
+            };
+            events.push(Ok(StreamEvent::Finish(finish_reason.to_owned())));
+        }
+    }
+}
+
+impl StreamDecoder for Decoder {
+    fn feed(&mut self, bytes: &[u8]) -> Vec<Result<StreamEvent, Error>> {
+        self.buffer.extend_from_slice(bytes);
+
+        let mut events = vec![];
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line = self.buffer.drain(..=end).collect::<Vec<u8>>();
+            self.on_line(&line, &mut events);
+        }
+
+        events
+    }
+
+    fn finish(&mut self) -> Vec<Result<StreamEvent, Error>> {
+        let mut events = vec![];
+        let line = std::mem::take(&mut self.buffer);
+        self.on_line(&line, &mut events);
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::mock::{request_body, serve};
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn streams_ndjson_from_a_mock_server() {
+        let body = concat!(
+            "{\"message\":{\"role\":\"assistant\",\"content\":\"Hel\"},\"done\":false}\n",
+            "{\"message\":{\"role\":\"assistant\",\"content\":\"lo\"},\"done\":false}\n",
+            "{\"message\":{\"role\":\"assistant\",\"content\":\"\"},\"done\":true,\"done_reason\":\"stop\",",
+            "\"prompt_eval_count\":10,\"eval_count\":5}",
+        );
+        let (url, request) = serve("application/x-ndjson", body).await;
+
+        let provider = Ollama {
+            http_client: Arc::new(reqwest::Client::new()),
+            api_url: url,
+        };
+        let messages = [ChatMessage::new(
+            Role::User,
+            vec![
+                Part::Text("hi".to_owned()),
+                Part::Image {
+                    media_type: "image/png".to_owned(),
+                    data: "AAAA".to_owned(),
+                },
+            ],
+        )];
+
+        let events = provider
+            .stream(&CompletionRequest {
+                model: "llama",
+                messages: &messages,
+                tools: &[],
+            })
+            .await
+            .expect("request failed")
+            .map(|event| event.expect("stream failed"))
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(
+            events,
+            vec![
+                StreamEvent::Text("Hel".to_owned()),
+                StreamEvent::Text("lo".to_owned()),
+                StreamEvent::Usage(Usage {
+                    prompt_tokens: 10,
+                    completion_tokens: 5,
+                }),
+                StreamEvent::Finish("stop".to_owned()),
+            ]
+        );
+
+        let request = request.await.expect("server failed");
+        assert!(request.starts_with("POST /api/chat"));
+
+        let body = request_body(&request);
+        assert_eq!(body["messages"][0]["content"], "hi");
+        assert_eq!(body["messages"][0]["images"][0], "AAAA");
+    }
+
+    #[test]
+    fn decodes_tool_calls() {
+        let mut decoder = Decoder::default();
+        let body = concat!(
+            "{\"message\":{\"role\":\"assistant\",\"content\":\"\",\"tool_calls\":",
+            "[{\"function\":{\"name\":\"list_speakers\",\"arguments\":{}}}]},\"done\":false}\n",
+            "{\"message\":{\"role\":\"assistant\",\"content\":\"\"},\"done\":true,\"done_reason\":\"stop\"}\n",
+        );
+
+        let events = decoder
+            .feed(body.as_bytes())
+            .into_iter()
+            .map(|event| event.expect("decode failed"))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            events,
+            vec![
+                StreamEvent::ToolCall(ToolCall {
+                    id: "call_1".to_owned(),
+                    name: "list_speakers".to_owned(),
+                    arguments: "{}".to_owned(),
+                }),
+                StreamEvent::Finish("tool_calls".to_owned()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,331 @@
+use poise::serenity_prelude::json;
+use std::sync::Arc;
+
+use super::{
+    decode_stream, ChatMessage, CompletionRequest, EventStream, Part, Provider, Role,
+    StreamDecoder, StreamEvent, ToolCall, Usage,
+};
+use crate::sse::{Chunk, CompletionDecoder};
+use crate::Error;
+
+/// OpenAI-compatible `/chat/completions` API
+pub struct OpenAi {
+    pub http_client: Arc<reqwest::Client>,
+    pub api_url: String,
+    pub token: String,
+    /// Whether to ask for the usage at the end of the stream, which some
+    /// compatible APIs reject
+    pub stream_usage: bool,
+}
+
+#[async_trait::async_trait]
+impl Provider for OpenAi {
+    async fn stream(&self, request: &CompletionRequest<'_>) -> Result<EventStream, Error> {
+        let mut body = json::json!({
+            "model": request.model,
+            "messages": request.messages.iter().map(encode_message).collect::<Vec<_>>(),
+            "stream": true,
+        });
+        if self.stream_usage {
+            body["stream_options"] = json::json!({ "include_usage": true });
+        }
+        if !request.tools.is_empty() {
+            body["tools"] = request
+                .tools
+                .iter()
+                .map(|tool| {
+                    json::json!({
+                        "type": "function",
+                        "function": {
+                            "name": tool.name,
+                            "description": tool.description,
+                            "parameters": tool.parameters,
+                        },
+                    })
+                })
+                .collect();
+        }
+
+        let response = self
+            .http_client
+            .post(format!("{}/chat/completions", self.api_url))
+            .bearer_auth(&self.token)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .await?;
+
+        decode_stream(response, Decoder::default()).await
+    }
+}
+
+fn encode_message(message: &ChatMessage) -> json::Value {
+    match message.role {
+        Role::System => json::json!({
+            "role": "system",
+            "content": message.joined_text(),
+        }),
+        Role::User => json::json!({
+            "role": "user",
+            "content": message
+                .content
+                .iter()
+                .map(|part| match part {
+                    Part::Text(text) => json::json!({
+                        "type": "text",
+                        "text": text,
+                    }),
+                    Part::Image { media_type, data } => json::json!({
+                        "type": "image_url",
+                        "image_url": {
+                            "url": format!("data:{media_type};base64,{data}"),
+                        },
+                    }),
+                })
+                .collect::<Vec<_>>(),
+        }),
+        Role::Assistant if !message.tool_calls.is_empty() => json::json!({
+            "role": "assistant",
+            "content": message.joined_text(),
+            "tool_calls": message
+                .tool_calls
+                .iter()
+                .map(|tool_call| json::json!({
+                    "id": tool_call.id,
+                    "type": "function",
+                    "function": {
+                        "name": tool_call.name,
+                        "arguments": tool_call.arguments,
+                    },
+                }))
+                .collect::<Vec<_>>(),
+        }),
+        Role::Assistant => json::json!({
+            "role": "assistant",
+            "content": message.joined_text(),
+        }),
+        Role::Tool => json::json!({
+            "role": "tool",
+            "tool_call_id": message.tool_call_id,
+            "content": message.joined_text(),
+        }),
+    }
+}
+
+/// Tool calls arrive as fragments and are emitted once complete
+#[derive(Default)]
+struct Decoder {
+    completion: CompletionDecoder,
+    tool_calls: Vec<ToolCall>,
+}
+
+impl Decoder {
+    fn on_chunk(&mut self, chunk: Chunk, events: &mut Vec<Result<StreamEvent, Error>>) {
+        let Chunk::Delta(delta) = chunk else {
+            self.flush_tool_calls(events);
+            return;
+        };
+
+        if let Some(content) = delta.content.filter(|content| !content.is_empty()) {
+            events.push(Ok(StreamEvent::Text(content)));
+        }
+
+        for fragment in delta.tool_calls {
+            if self.tool_calls.len() <= fragment.index {
+                self.tool_calls
+                    .resize_with(fragment.index + 1, ToolCall::default);
+            }
+            let tool_call = &mut self.tool_calls[fragment.index];
+            tool_call.id.push_str(fragment.id.as_deref().unwrap_or(""));
+            tool_call
+                .name
+                .push_str(fragment.name.as_deref().unwrap_or(""));
+            tool_call
+                .arguments
+                .push_str(fragment.arguments.as_deref().unwrap_or(""));
+        }
+
+        if let Some(prompt_tokens) = delta.prompt_tokens {
+            events.push(Ok(StreamEvent::Usage(Usage {
+                prompt_tokens,
+                completion_tokens: delta.completion_tokens.unwrap_or(0),
+            })));
+        }
+
+        if let Some(finish_reason) = delta.finish_reason {
+            self.flush_tool_calls(events);
+            events.push(Ok(StreamEvent::Finish(finish_reason)));
+        }
+    }
+
+    fn flush_tool_calls(&mut self, events: &mut Vec<Result<StreamEvent, Error>>) {
+        events.extend(
+            self.tool_calls
+                .drain(..)
+                .map(|tool_call| Ok(StreamEvent::ToolCall(tool_call))),
+        );
+    }
+}
+
+impl StreamDecoder for Decoder {
+    fn feed(&mut self, bytes: &[u8]) -> Vec<Result<StreamEvent, Error>> {
+        let mut events = vec![];
+
+        for chunk in self.completion.feed(bytes) {
+            match chunk {
+                Ok(chunk) => self.on_chunk(chunk, &mut events),
+                Err(e) => events.push(Err(e.into())),
+            }
+        }
+
+        events
+    }
+
+    fn finish(&mut self) -> Vec<Result<StreamEvent, Error>> {
+        let mut events = vec![];
+
+        match self.completion.finish() {
+            Some(Ok(chunk)) => self.on_chunk(chunk, &mut events),
+            Some(Err(e)) => events.push(Err(e.into())),
+            None => {}
+        }
+        self.flush_tool_calls(&mut events);
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::mock::{request_body, serve, serve_response};
+    use crate::llm::ProviderError;
+    use crate::llm::Tool;
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn streams_text_and_tool_calls_from_a_mock_server() {
+        let body = concat!(
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hel\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"lo\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"id\":\"call_1\",",
+            "\"function\":{\"name\":\"list_speakers\",\"arguments\":\"{\\\"a\\\"\"}}]}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,",
+            "\"function\":{\"arguments\":\":1}\"}}]}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{},\"finish_reason\":\"tool_calls\"}]}\n\n",
+            "data: {\"choices\":[],\"usage\":{\"prompt_tokens\":10,\"completion_tokens\":5}}\n\n",
+            "data: [DONE]\n\n",
+        );
+        let (url, request) = serve("text/event-stream", body).await;
+
+        let provider = OpenAi {
+            http_client: Arc::new(reqwest::Client::new()),
+            api_url: url,
+            token: "token".to_owned(),
+            stream_usage: true,
+        };
+        let messages = [
+            ChatMessage::text(Role::System, "system"),
+            ChatMessage::new(
+                Role::User,
+                vec![
+                    Part::Text("hi".to_owned()),
+                    Part::Image {
+                        media_type: "image/png".to_owned(),
+                        data: "AAAA".to_owned(),
+                    },
+                ],
+            ),
+        ];
+        let tools = [Tool {
+            name: "list_speakers".to_owned(),
+            description: "list".to_owned(),
+            parameters: json::json!({ "type": "object" }),
+        }];
+
+        let events = provider
+            .stream(&CompletionRequest {
+                model: "gpt",
+                messages: &messages,
+                tools: &tools,
+            })
+            .await
+            .expect("request failed")
+            .map(|event| event.expect("stream failed"))
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(
+            events,
+            vec![
+                StreamEvent::Text("Hel".to_owned()),
+                StreamEvent::Text("lo".to_owned()),
+                StreamEvent::ToolCall(ToolCall {
+                    id: "call_1".to_owned(),
+                    name: "list_speakers".to_owned(),
+                    arguments: "{\"a\":1}".to_owned(),
+                }),
+                StreamEvent::Finish("tool_calls".to_owned()),
+                StreamEvent::Usage(Usage {
+                    prompt_tokens: 10,
+                    completion_tokens: 5,
+                }),
+            ]
+        );
+
+        let request = request.await.expect("server failed");
+        assert!(request.starts_with("POST /chat/completions"));
+        assert!(request.contains("authorization: Bearer token"));
+
+        let body = request_body(&request);
+        assert_eq!(body["model"], "gpt");
+        assert_eq!(
+            body["messages"][1]["content"][1]["image_url"]["url"],
+            "data:image/png;base64,AAAA"
+        );
+        assert_eq!(body["tools"][0]["function"]["name"], "list_speakers");
+        assert_eq!(body["stream_options"]["include_usage"], true);
+    }
+
+    #[tokio::test]
+    async fn reports_http_errors() {
+        let (url, request) = serve_response(
+            "429 Too Many Requests",
+            "Content-Type: application/json\r\nRetry-After: 20\r\n",
+            "{\"error\":{\"message\":\"Rate limit reached\"}}",
+        )
+        .await;
+
+        let provider = OpenAi {
+            http_client: Arc::new(reqwest::Client::new()),
+            api_url: url,
+            token: "token".to_owned(),
+            stream_usage: false,
+        };
+        let messages = [ChatMessage::text(Role::User, "hi")];
+
+        let error = match provider
+            .stream(&CompletionRequest {
+                model: "gpt",
+                messages: &messages,
+                tools: &[],
+            })
+            .await
+        {
+            Ok(_) => panic!("request succeeded"),
+            Err(e) => e,
+        };
+
+        assert_eq!(
+            error.downcast_ref::<ProviderError>(),
+            Some(&ProviderError::Http {
+                status: 429,
+                message: "Rate limit reached".to_owned(),
+                retry_after: Some(20),
+            })
+        );
+
+        let request = request.await.expect("server failed");
+        assert!(request_body(&request)["stream_options"].is_null());
+    }
+}
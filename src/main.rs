@@ -2,24 +2,41 @@
 
 mod chat;
 mod db;
+mod document;
+mod image;
+mod knowledge;
+mod llm;
+mod models;
 mod remind;
+mod reply;
+mod session;
+mod split;
+mod sse;
+mod tools;
 mod voice;
+mod voicevox;
 
 use poise::serenity_prelude::{self as serenity, User};
 use songbird::SerenityInit;
 use std::env::var;
+use std::future::Future;
 use std::sync::Arc;
+use tokio::time::{timeout, Duration};
 use voice::build_voice;
 
 // Types used by all command functions
 type Error = Box<dyn std::error::Error + Send + Sync>;
 type Context<'a> = poise::Context<'a, Data, Error>;
 
+const VOICE_HANDLER_TIMEOUT: Duration = Duration::from_secs(60);
+const CHAT_HANDLER_TIMEOUT: Duration = Duration::from_secs(300);
+
 // Custom user data passed to all command functions
 pub struct Data {
-    voice: voice::Voice,
-    chat: chat::Chat,
+    voice: Arc<voice::Voice>,
+    chat: Arc<chat::Chat>,
     remind: remind::Remind,
+    knowledge: Option<Arc<knowledge::Knowledge>>,
 }
 
 async fn on_error(error: poise::FrameworkError<'_, Data, Error>) {
@@ -52,11 +69,25 @@ async fn main() {
         commands: vec![
             voice::connect_vc(),
             voice::disconnect_vc(),
+            voice::vc_status(),
             voice::show_vc(),
             voice::set_vc(),
             voice::show_vc_info(),
             voice::show_vcs_info(),
             remind::remind(),
+            chat::set_system_prompt(),
+            chat::reset_system_prompt(),
+            chat::show_system_prompt(),
+            chat::channel_history(),
+            chat::model(),
+            chat::ask(),
+            chat::chat(),
+            chat::thread_chat(),
+            chat::usage(),
+            chat::summarize(),
+            chat::translate(),
+            chat::memory(),
+            knowledge::knowledge(),
         ],
         // The global error handler for all error cases that may occur
         on_error: |error| Box::pin(on_error(error)),
@@ -73,13 +104,33 @@ async fn main() {
                 poise::builtins::register_globally(ctx, &framework.options().commands).await?;
 
                 let user: Arc<User> = Arc::new(_ready.user.clone().into());
+                let voice = Arc::new(
+                    build_voice(Arc::clone(&http_client), Arc::clone(&db))
+                        .expect("Failed to initialize voice"),
+                );
+
+                let remind = remind::build_remind(Arc::clone(&db), Arc::clone(&voice))?;
+                let tools = tools::build_tools(Arc::clone(&db), remind.clone(), Arc::clone(&voice));
+                let knowledge =
+                    knowledge::build_knowledge(Arc::clone(&http_client), Arc::clone(&db))
+                        .await?
+                        .map(Arc::new);
 
                 Ok(Data {
-                    voice: build_voice(Arc::clone(&http_client), Arc::clone(&db))
-                        .expect("Failed to initialize voice"),
-                    chat: chat::build_chat(Arc::clone(&http_client), Arc::clone(&user))
+                    voice: Arc::clone(&voice),
+                    chat: Arc::new(
+                        chat::build_chat(
+                            Arc::clone(&http_client),
+                            Arc::clone(&user),
+                            Arc::clone(&db),
+                            tools,
+                            knowledge.clone(),
+                        )
+                        .await
                         .expect("Failed to initialize chat"),
-                    remind: remind::build_remind(Arc::clone(&db))?,
+                    ),
+                    remind,
+                    knowledge,
                 })
             })
         })
@@ -116,12 +167,112 @@ async fn event_handler(
                 let _ = remind_clone.invoke_reminders(&ctx_clone).await;
             });
         }
+        // voice states are only known once the guilds are cached, so sessions
+        // are restored on CacheReady rather than Ready
+        serenity::FullEvent::CacheReady { guilds: _ } => {
+            let voice = Arc::clone(&data.voice);
+            let ctx_clone = ctx.clone();
+            tokio::spawn(async move {
+                if let Err(e) = voice.restore_sessions(&ctx_clone).await {
+                    log::error!("Failed to restore voice sessions: {e}");
+                }
+            });
+        }
         serenity::FullEvent::Message { new_message } => {
-            data.voice.on_message(ctx, new_message).await?;
-            data.chat.on_message(ctx, new_message).await?;
+            let voice = Arc::clone(&data.voice);
+            let ctx_clone = ctx.clone();
+            let message = new_message.clone();
+            spawn_handler("voice", VOICE_HANDLER_TIMEOUT, async move {
+                voice.on_message(&ctx_clone, &message).await
+            });
+
+            let chat = Arc::clone(&data.chat);
+            let ctx_clone = ctx.clone();
+            let message = new_message.clone();
+            spawn_handler("chat", CHAT_HANDLER_TIMEOUT, async move {
+                chat.on_message(&ctx_clone, &message).await
+            });
+
+            if let Some(knowledge) = &data.knowledge {
+                let knowledge = Arc::clone(knowledge);
+                let message = new_message.clone();
+                spawn_handler("knowledge", CHAT_HANDLER_TIMEOUT, async move {
+                    knowledge.on_message(&message).await
+                });
+            }
+        }
+        serenity::FullEvent::MessageDelete {
+            channel_id,
+            deleted_message_id,
+            ..
+        } => {
+            if let Some(knowledge) = &data.knowledge {
+                let knowledge = Arc::clone(knowledge);
+                let (channel_id, message_id) = (*channel_id, *deleted_message_id);
+                spawn_handler("knowledge", CHAT_HANDLER_TIMEOUT, async move {
+                    knowledge.on_message_delete(channel_id, &[message_id]).await
+                });
+            }
+        }
+        serenity::FullEvent::MessageDeleteBulk {
+            channel_id,
+            multiple_deleted_messages_ids,
+            ..
+        } => {
+            if let Some(knowledge) = &data.knowledge {
+                let knowledge = Arc::clone(knowledge);
+                let channel_id = *channel_id;
+                let message_ids = multiple_deleted_messages_ids.clone();
+                spawn_handler("knowledge", CHAT_HANDLER_TIMEOUT, async move {
+                    knowledge.on_message_delete(channel_id, &message_ids).await
+                });
+            }
+        }
+        serenity::FullEvent::MessageUpdate { event, .. } => {
+            if let Some(knowledge) = &data.knowledge {
+                let knowledge = Arc::clone(knowledge);
+                let ctx_clone = ctx.clone();
+                let event = event.clone();
+                spawn_handler("knowledge", CHAT_HANDLER_TIMEOUT, async move {
+                    knowledge.on_message_update(&ctx_clone, &event).await
+                });
+            }
+        }
+        serenity::FullEvent::ReactionAdd { add_reaction } => {
+            let chat = Arc::clone(&data.chat);
+            let ctx_clone = ctx.clone();
+            let reaction = add_reaction.clone();
+            spawn_handler("chat", CHAT_HANDLER_TIMEOUT, async move {
+                chat.on_reaction(&ctx_clone, &reaction).await
+            });
+        }
+        serenity::FullEvent::InteractionCreate {
+            interaction: serenity::Interaction::Component(interaction),
+        } if interaction.data.custom_id.starts_with(reply::BUTTON_PREFIX) => {
+            let chat = Arc::clone(&data.chat);
+            let ctx_clone = ctx.clone();
+            let interaction = interaction.clone();
+            spawn_handler("chat", CHAT_HANDLER_TIMEOUT, async move {
+                chat.on_component(&ctx_clone, &interaction).await
+            });
         }
         _ => {}
     }
 
     Ok(())
 }
+
+/// Runs a subsystem handler in its own task so that a failing or slow
+/// subsystem neither affects the others nor blocks the gateway loop.
+fn spawn_handler<F>(name: &'static str, limit: Duration, handler: F)
+where
+    F: Future<Output = Result<(), Error>> + Send + 'static,
+{
+    tokio::spawn(async move {
+        match timeout(limit, handler).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => log::error!("Error in {name} handler: {e}"),
+            Err(_) => log::error!("{name} handler timed out after {limit:?}"),
+        }
+    });
+}
@@ -0,0 +1,111 @@
+use std::collections::BTreeMap;
+use std::env::var;
+
+/// The models users are allowed to pick, configured by `LLM_MODELS` as
+/// comma separated `alias=model` pairs, e.g. `fast=gpt-4o-mini,smart=gpt-4o`
+pub struct Models {
+    default_model: String,
+    aliases: BTreeMap<String, String>,
+}
+
+pub fn build_models() -> Models {
+    let default_model = var("LLM_MODEL").unwrap_or(String::from(""));
+    let aliases = var("LLM_MODELS")
+        .map(|models| parse_aliases(&models))
+        .unwrap_or_default();
+
+    Models {
+        default_model,
+        aliases,
+    }
+}
+
+fn parse_aliases(models: &str) -> BTreeMap<String, String> {
+    models
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let (alias, model) = match entry.split_once('=') {
+                Some((alias, model)) => (alias.trim(), model.trim()),
+                None => (entry, entry),
+            };
+            if alias.is_empty() || model.is_empty() {
+                log::warn!("Ignoring malformed LLM_MODELS entry {entry}");
+                return None;
+            }
+            Some((alias.to_owned(), model.to_owned()))
+        })
+        .collect()
+}
+
+impl Models {
+    pub fn default_model(&self) -> &str {
+        &self.default_model
+    }
+
+    /// Resolves an alias or the full name of an allowed model
+    pub fn resolve(&self, name: &str) -> Option<&str> {
+        if let Some(model) = self.aliases.get(name) {
+            return Some(model);
+        }
+        if name == self.default_model {
+            return Some(&self.default_model);
+        }
+
+        self.aliases
+            .values()
+            .find(|model| *model == name)
+            .map(String::as_str)
+    }
+
+    /// One `alias: model` line per allowed model
+    pub fn describe(&self) -> String {
+        let mut lines = vec![format!("default: {}", self.default_model)];
+        lines.extend(
+            self.aliases
+                .iter()
+                .map(|(alias, model)| format!("{alias}: {model}")),
+        );
+
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn models(aliases: &str) -> Models {
+        Models {
+            default_model: "gpt-4o".to_owned(),
+            aliases: parse_aliases(aliases),
+        }
+    }
+
+    #[test]
+    fn parses_aliases() {
+        let aliases = parse_aliases(" fast = gpt-4o-mini ,, o3 ,=nameless,empty=, smart=gpt-4o");
+
+        assert_eq!(
+            aliases.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("fast".to_owned(), "gpt-4o-mini".to_owned()),
+                ("o3".to_owned(), "o3".to_owned()),
+                ("smart".to_owned(), "gpt-4o".to_owned()),
+            ]
+        );
+        assert!(parse_aliases("").is_empty());
+    }
+
+    #[test]
+    fn resolves_aliases_and_allowed_models() {
+        let models = models("fast=gpt-4o-mini");
+
+        assert_eq!(models.resolve("fast"), Some("gpt-4o-mini"));
+        assert_eq!(models.resolve("gpt-4o-mini"), Some("gpt-4o-mini"));
+        assert_eq!(models.resolve("gpt-4o"), Some("gpt-4o"));
+        assert_eq!(models.resolve("slow"), None);
+        assert_eq!(models.resolve(""), None);
+    }
+}
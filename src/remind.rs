@@ -6,20 +6,22 @@ use tokio::time::{interval, Duration};
 
 use regex::Regex;
 
-use mongodb::bson::doc;
+use mongodb::bson::{doc, Document};
 
 use chrono_tz::Asia::Tokyo;
 
 use crate::db::Db;
+use crate::voice::Voice;
 use crate::{Context, Error};
 
 #[derive(Clone)]
 pub struct Remind {
     db: Arc<Db>,
+    voice: Arc<Voice>,
     patterns: Vec<Regex>,
 }
 
-pub fn build_remind(db: Arc<Db>) -> Result<Remind, Error> {
+pub fn build_remind(db: Arc<Db>, voice: Arc<Voice>) -> Result<Remind, Error> {
     const PATTERNS: [&str; 2] = [
         r"^(?:(\d+)/(\d+)\s+)*(\d+):(\d+)\s+(.+)$",
         r"^(?:(\d+)h)*(?:(\d+)m)*\s+(.+)$",
@@ -30,12 +32,22 @@ pub fn build_remind(db: Arc<Db>) -> Result<Remind, Error> {
         .map(|&pattern| Regex::new(pattern).expect("Failed to compile regex"))
         .collect();
 
-    Ok(Remind { db, patterns })
+    Ok(Remind {
+        db,
+        voice,
+        patterns,
+    })
 }
 
 impl Remind {
-    pub async fn remind(&self, ctx: Context<'_>, message: String) -> Result<(), Error> {
+    pub async fn remind(
+        &self,
+        ctx: Context<'_>,
+        message: String,
+        voice: Option<bool>,
+    ) -> Result<(), Error> {
         let channel_id: u64 = ctx.channel_id().into();
+        let guild_id: Option<u64> = ctx.guild_id().map(|guild_id| guild_id.into());
         let user_id: u64 = ctx.author().id.into();
 
         let now_jst = chrono::Utc::now().with_timezone(&Tokyo);
@@ -115,7 +127,14 @@ impl Remind {
         }
 
         self.db
-            .add_new_reminder(user_id, channel_id, content.clone(), final_dst.to_utc())
+            .add_new_reminder(
+                user_id,
+                channel_id,
+                guild_id,
+                content.clone(),
+                final_dst.to_utc(),
+                voice.unwrap_or(false),
+            )
             .await?;
 
         ctx.say(format!(
@@ -128,6 +147,27 @@ impl Remind {
         Ok(())
     }
 
+    pub async fn create_reminder(
+        &self,
+        user_id: u64,
+        channel_id: u64,
+        guild_id: Option<u64>,
+        content: String,
+        remind_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<(), Error> {
+        self.db
+            .add_new_reminder(user_id, channel_id, guild_id, content, remind_at, false)
+            .await
+    }
+
+    pub async fn list_reminders(&self, user_id: u64) -> Result<Vec<Document>, Error> {
+        self.db.get_user_reminders(user_id).await
+    }
+
+    pub async fn cancel_reminder(&self, user_id: u64, id: &str) -> Result<bool, Error> {
+        self.db.remove_user_reminder(user_id, id).await
+    }
+
     pub async fn invoke_reminders(&self, ctx: &serenity::Context) -> Result<(), Error> {
         let mut interval = interval(Duration::from_secs(10));
         loop {
@@ -146,15 +186,34 @@ impl Remind {
             let channel_id = reminder.get_str("channel_id")?.parse::<u64>()?;
             let user_id = reminder.get_str("user_id")?.parse::<u64>()?;
             let channel = serenity::ChannelId::new(channel_id);
+            let content = reminder.get_str("content")?;
 
-            channel
-                .say(
-                    ctx,
-                    format!("<@{}> {}", user_id, reminder.get_str("content")?),
-                )
-                .await?;
+            channel.say(ctx, format!("<@{user_id}> {content}")).await?;
+
+            let voice_guild_id = reminder
+                .get_bool("voice")
+                .unwrap_or(false)
+                .then(|| reminder.get_str("guild_id").ok())
+                .flatten()
+                .map(str::parse)
+                .transpose()?
+                .map(serenity::GuildId::new);
+            let content = content.to_owned();
 
             self.db.remove_reminder(reminder).await?;
+
+            // VOICEVOX may take a while, which would delay the other reminders
+            if let Some(guild_id) = voice_guild_id {
+                let voice = Arc::clone(&self.voice);
+                let ctx = ctx.clone();
+                let user_id = serenity::UserId::new(user_id);
+
+                tokio::spawn(async move {
+                    if let Err(e) = voice.read_aloud(&ctx, guild_id, user_id, &content).await {
+                        log::error!("Error reading reminder aloud: {e}");
+                    }
+                });
+            }
         }
 
         let removed = self.db.remove_old_reminders().await?;
@@ -181,6 +240,10 @@ impl Remind {
 
 /// Set reminder
 #[poise::command(slash_command)]
-pub async fn remind(ctx: Context<'_>, message: String) -> Result<(), Error> {
-    ctx.data().remind.remind(ctx, message).await
+pub async fn remind(
+    ctx: Context<'_>,
+    message: String,
+    #[description = "Also read the reminder aloud in vc"] voice: Option<bool>,
+) -> Result<(), Error> {
+    ctx.data().remind.remind(ctx, message, voice).await
 }
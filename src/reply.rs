@@ -0,0 +1,298 @@
+use poise::serenity_prelude::{
+    self as serenity, ButtonStyle, ComponentInteraction, CreateActionRow, CreateAllowedMentions,
+    CreateButton, CreateInteractionResponseFollowup, CreateMessage, EditInteractionResponse,
+    EditMessage, Message, MessageId,
+};
+use poise::{CreateReply, ReplyHandle};
+
+use crate::split::split_message;
+use crate::{Context, Error};
+
+pub const MAX_MESSAGE_LENGTH: usize = 2000;
+/// Custom ids of the buttons are `chat:stop:{key}` and `chat:regenerate:{key}`
+pub const BUTTON_PREFIX: &str = "chat:";
+
+/// Where a reply is rendered, together with the messages sent so far.
+/// The first message carries a stop button while the reply is streamed and
+/// a regenerate button once it is finished.
+pub struct Output<'a> {
+    target: Target<'a>,
+    /// Identifies the conversation in the custom ids of the buttons
+    key: u64,
+    finished: bool,
+}
+
+enum Target<'a> {
+    /// Replies to a mention
+    Message {
+        message: &'a serenity::Message,
+        sent: Vec<Message>,
+    },
+    /// Responds to a slash command
+    Command {
+        ctx: Context<'a>,
+        ephemeral: bool,
+        sent: Vec<(ReplyHandle<'a>, String)>,
+    },
+    /// Regenerates a reply from a button. The first piece replaces the
+    /// message with the button and the rest are sent as followups.
+    Component {
+        interaction: &'a ComponentInteraction,
+        ephemeral: bool,
+        sent: Vec<(Option<MessageId>, String)>,
+    },
+}
+
+impl<'a> Output<'a> {
+    pub fn message(message: &'a serenity::Message) -> Output<'a> {
+        Output {
+            target: Target::Message {
+                message,
+                sent: vec![],
+            },
+            key: message.id.get(),
+            finished: false,
+        }
+    }
+
+    pub fn command(ctx: Context<'a>, ephemeral: bool) -> Output<'a> {
+        Output {
+            target: Target::Command {
+                ctx,
+                ephemeral,
+                sent: vec![],
+            },
+            key: ctx.id(),
+            finished: false,
+        }
+    }
+
+    pub fn component(
+        interaction: &'a ComponentInteraction,
+        key: u64,
+        ephemeral: bool,
+    ) -> Output<'a> {
+        Output {
+            target: Target::Component {
+                interaction,
+                ephemeral,
+                sent: vec![],
+            },
+            key,
+            finished: false,
+        }
+    }
+
+    pub fn key(&self) -> u64 {
+        self.key
+    }
+
+    pub fn is_ephemeral(&self) -> bool {
+        match &self.target {
+            Target::Message { .. } => false,
+            Target::Command { ephemeral, .. } | Target::Component { ephemeral, .. } => *ephemeral,
+        }
+    }
+
+    /// Shows the typing indicator in the channel until dropped. Ephemeral
+    /// replies show Discord's own "thinking" state instead.
+    pub fn start_typing(&self, ctx: &serenity::Context) -> Option<serenity::Typing> {
+        match &self.target {
+            Target::Message { message, .. } => Some(message.channel_id.start_typing(&ctx.http)),
+            Target::Component {
+                interaction,
+                ephemeral: false,
+                ..
+            } => Some(interaction.channel_id.start_typing(&ctx.http)),
+            Target::Command { .. } | Target::Component { .. } => None,
+        }
+    }
+
+    /// Splits the reply into messages, editing the ones already sent and
+    /// sending new ones as the reply grows
+    pub async fn render(
+        &mut self,
+        ctx: &serenity::Context,
+        reply: &str,
+        finished: bool,
+    ) -> Result<(), Error> {
+        let buttons_changed = finished != self.finished;
+        self.finished = finished;
+
+        for (i, piece) in split_message(reply, MAX_MESSAGE_LENGTH)
+            .into_iter()
+            .enumerate()
+        {
+            let components = if i == 0 {
+                buttons(self.key, finished)
+            } else {
+                vec![]
+            };
+            let force = i == 0 && buttons_changed;
+
+            match &mut self.target {
+                Target::Message { message, sent } => match sent.get_mut(i) {
+                    Some(my_message) => {
+                        if force || my_message.content != piece {
+                            my_message
+                                .edit(
+                                    ctx,
+                                    EditMessage::new().content(piece).components(components),
+                                )
+                                .await?;
+                        }
+                    }
+                    None => {
+                        // the same as Message::reply, which can't attach components
+                        let allowed_mentions = CreateAllowedMentions::new()
+                            .replied_user(false)
+                            .everyone(true)
+                            .all_users(true)
+                            .all_roles(true);
+                        let my_message = message
+                            .channel_id
+                            .send_message(
+                                ctx,
+                                CreateMessage::new()
+                                    .content(piece)
+                                    .components(components)
+                                    .reference_message(*message)
+                                    .allowed_mentions(allowed_mentions),
+                            )
+                            .await?;
+                        sent.push(my_message);
+                    }
+                },
+                Target::Command {
+                    ctx: command_ctx,
+                    ephemeral,
+                    sent,
+                } => match sent.get_mut(i) {
+                    Some((handle, content)) => {
+                        if force || *content != piece {
+                            handle
+                                .edit(
+                                    *command_ctx,
+                                    CreateReply::default()
+                                        .content(&piece)
+                                        .components(components),
+                                )
+                                .await?;
+                            *content = piece;
+                        }
+                    }
+                    None => {
+                        let handle = command_ctx
+                            .send(
+                                CreateReply::default()
+                                    .content(&piece)
+                                    .components(components)
+                                    .ephemeral(*ephemeral),
+                            )
+                            .await?;
+                        sent.push((handle, piece));
+                    }
+                },
+                Target::Component {
+                    interaction,
+                    ephemeral,
+                    sent,
+                } => match sent.get_mut(i) {
+                    Some((message_id, content)) => {
+                        if force || *content != piece {
+                            match message_id {
+                                Some(message_id) => {
+                                    interaction
+                                        .edit_followup(
+                                            ctx,
+                                            *message_id,
+                                            CreateInteractionResponseFollowup::new()
+                                                .content(&piece),
+                                        )
+                                        .await?;
+                                }
+                                None => {
+                                    interaction
+                                        .edit_response(
+                                            ctx,
+                                            EditInteractionResponse::new()
+                                                .content(&piece)
+                                                .components(components),
+                                        )
+                                        .await?;
+                                }
+                            }
+                            *content = piece;
+                        }
+                    }
+                    None if i == 0 => {
+                        interaction
+                            .edit_response(
+                                ctx,
+                                EditInteractionResponse::new()
+                                    .content(&piece)
+                                    .components(components),
+                            )
+                            .await?;
+                        sent.push((None, piece));
+                    }
+                    None => {
+                        let followup = interaction
+                            .create_followup(
+                                ctx,
+                                CreateInteractionResponseFollowup::new()
+                                    .content(&piece)
+                                    .ephemeral(*ephemeral),
+                            )
+                            .await?;
+                        sent.push((Some(followup.id), piece));
+                    }
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The messages sent as the reply
+    pub async fn messages(&self, ctx: &serenity::Context) -> Result<Vec<Message>, Error> {
+        match &self.target {
+            Target::Message { sent, .. } => Ok(sent.clone()),
+            Target::Command { sent, .. } => {
+                let mut messages = vec![];
+                for (handle, _) in sent {
+                    messages.push(handle.message().await?.into_owned());
+                }
+
+                Ok(messages)
+            }
+            Target::Component {
+                interaction, sent, ..
+            } => {
+                let mut messages = vec![];
+                for (message_id, _) in sent {
+                    messages.push(match message_id {
+                        Some(message_id) => interaction.get_followup(ctx, *message_id).await?,
+                        None => interaction.get_response(ctx).await?,
+                    });
+                }
+
+                Ok(messages)
+            }
+        }
+    }
+}
+
+fn buttons(key: u64, finished: bool) -> Vec<CreateActionRow> {
+    let button = if finished {
+        CreateButton::new(format!("{BUTTON_PREFIX}regenerate:{key}"))
+            .label("Regenerate")
+            .style(ButtonStyle::Secondary)
+    } else {
+        CreateButton::new(format!("{BUTTON_PREFIX}stop:{key}"))
+            .label("Stop")
+            .style(ButtonStyle::Danger)
+    };
+
+    vec![CreateActionRow::Buttons(vec![button])]
+}
@@ -0,0 +1,55 @@
+use chrono::{DateTime, Utc};
+use poise::serenity_prelude as serenity;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[derive(Clone)]
+pub struct Session {
+    pub voice_channel_id: serenity::ChannelId,
+    pub text_channel_id: serenity::ChannelId,
+    pub started_at: DateTime<Utc>,
+    pub messages_read: u64,
+}
+
+/// Voice sessions of the guilds the bot is currently connected to
+#[derive(Default)]
+pub struct Sessions {
+    sessions: Mutex<HashMap<serenity::GuildId, Session>>,
+}
+
+impl Sessions {
+    pub fn start(
+        &self,
+        guild_id: serenity::GuildId,
+        voice_channel_id: serenity::ChannelId,
+        text_channel_id: serenity::ChannelId,
+    ) {
+        self.lock().insert(
+            guild_id,
+            Session {
+                voice_channel_id,
+                text_channel_id,
+                started_at: Utc::now(),
+                messages_read: 0,
+            },
+        );
+    }
+
+    pub fn end(&self, guild_id: serenity::GuildId) -> Option<Session> {
+        self.lock().remove(&guild_id)
+    }
+
+    pub fn get(&self, guild_id: serenity::GuildId) -> Option<Session> {
+        self.lock().get(&guild_id).cloned()
+    }
+
+    pub fn count_read(&self, guild_id: serenity::GuildId) {
+        if let Some(session) = self.lock().get_mut(&guild_id) {
+            session.messages_read += 1;
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<serenity::GuildId, Session>> {
+        self.sessions.lock().expect("sessions lock poisoned")
+    }
+}
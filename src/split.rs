@@ -0,0 +1,149 @@
+const FENCE: &str = "```";
+const CLOSING_FENCE: &str = "\n```";
+
+/// Splits text into pieces of at most `max_len` characters.
+/// Paragraph and line boundaries are preferred over cutting mid-line, and
+/// code fences that span a split are closed and reopened with the same
+/// language tag in the next piece.
+pub fn split_message(text: &str, max_len: usize) -> Vec<String> {
+    let mut pieces = vec![];
+    let mut rest = text;
+    let mut fence: Option<String> = None;
+
+    while !rest.is_empty() {
+        let prefix = match &fence {
+            Some(lang) => format!("{FENCE}{lang}\n"),
+            None => String::new(),
+        };
+        let prefix_len = prefix.chars().count();
+
+        if prefix_len + rest.chars().count() <= max_len {
+            pieces.push(prefix + rest);
+            break;
+        }
+
+        let budget = max_len
+            .saturating_sub(prefix_len + CLOSING_FENCE.len())
+            .max(1);
+        let (piece, next) = split_at_boundary(rest, budget);
+
+        let mut chunk = prefix;
+        chunk.push_str(piece.trim_end_matches('\n'));
+        fence = fence_after(fence, piece);
+        if fence.is_some() {
+            chunk.push_str(CLOSING_FENCE);
+        }
+
+        pieces.push(chunk);
+        rest = next;
+    }
+
+    pieces
+}
+
+/// Returns the piece that fits into `budget` characters and the rest
+fn split_at_boundary(text: &str, budget: usize) -> (&str, &str) {
+    let window_end = text
+        .char_indices()
+        .nth(budget)
+        .map_or(text.len(), |(i, _)| i);
+    let window = &text[..window_end];
+
+    if let Some(i) = window.rfind("\n\n").filter(|&i| i > 0) {
+        return (&text[..i], text[i..].trim_start_matches('\n'));
+    }
+    if let Some(i) = window.rfind('\n').filter(|&i| i > 0) {
+        return (&text[..i], &text[i + 1..]);
+    }
+    if let Some(i) = window.rfind(' ').filter(|&i| i > 0) {
+        return (&text[..i], &text[i + 1..]);
+    }
+
+    (window, &text[window_end..])
+}
+
+/// Cuts the text after `max_len` characters, marking the cut with an ellipsis
+pub fn truncate(text: &str, max_len: usize) -> String {
+    match text.char_indices().nth(max_len) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_owned(),
+    }
+}
+
+/// Tracks whether the text ends inside a code fence, and with which language
+fn fence_after(mut fence: Option<String>, text: &str) -> Option<String> {
+    for line in text.lines() {
+        if let Some(rest) = line.trim_start().strip_prefix(FENCE) {
+            fence = match fence {
+                Some(_) => None,
+                None => Some(rest.trim().to_owned()),
+            };
+        }
+    }
+
+    fence
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_short_text_as_is() {
+        assert_eq!(split_message("hello", 10), vec!["hello"]);
+        assert_eq!(split_message("", 10), Vec::<String>::new());
+    }
+
+    #[test]
+    fn prefers_paragraph_boundaries() {
+        let text = "first line\nsecond\n\nthird paragraph";
+
+        assert_eq!(
+            split_message(text, 25),
+            vec!["first line\nsecond", "third paragraph"]
+        );
+    }
+
+    #[test]
+    fn falls_back_to_line_and_word_boundaries() {
+        assert_eq!(
+            split_message("aaaa\nbbbb\ncccc", 12),
+            vec!["aaaa", "bbbb\ncccc"]
+        );
+        assert_eq!(
+            split_message("aaaa bbbb cccc", 12),
+            vec!["aaaa", "bbbb cccc"]
+        );
+    }
+
+    #[test]
+    fn cuts_long_words_on_char_boundaries() {
+        let pieces = split_message("あいうえおかきくけこ", 8);
+
+        assert_eq!(pieces, vec!["あいうえ", "おかきくけこ"]);
+    }
+
+    #[test]
+    fn reopens_code_fences_with_language() {
+        let text = "intro\n```rust\nlet a = 1;\nlet b = 2;\nlet c = 3;\n```\noutro";
+        let pieces = split_message(text, 30);
+
+        assert_eq!(
+            pieces,
+            vec![
+                "intro\n```rust\nlet a = 1;\n```",
+                "```rust\nlet b = 2;\n```",
+                "```rust\nlet c = 3;\n```\noutro",
+            ]
+        );
+    }
+
+    #[test]
+    fn never_exceeds_the_limit() {
+        let text = "```\n".to_owned() + &"0123456789\n".repeat(50) + "```\n" + &"word ".repeat(100);
+
+        for piece in split_message(&text, 40) {
+            assert!(piece.chars().count() <= 40, "{piece:?}");
+        }
+    }
+}
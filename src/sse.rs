@@ -0,0 +1,353 @@
+use poise::serenity_prelude::json;
+use std::fmt;
+
+/// A server-sent event
+#[derive(Debug, PartialEq)]
+pub struct Event {
+    pub event: Option<String>,
+    pub data: String,
+}
+
+/// Incremental decoder for `text/event-stream` bodies.
+/// Bytes may be fed in arbitrary chunks; events are yielded once complete.
+#[derive(Default)]
+pub struct Decoder {
+    buffer: Vec<u8>,
+    event: Option<String>,
+    data: Option<String>,
+}
+
+impl Decoder {
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<Event> {
+        self.buffer.extend_from_slice(bytes);
+
+        let mut events = vec![];
+        let mut start = 0;
+
+        while let Some(offset) = self.buffer[start..]
+            .iter()
+            .position(|&b| b == b'\n' || b == b'\r')
+        {
+            let end = start + offset;
+            let next = match self.buffer[end] {
+                b'\r' => match self.buffer.get(end + 1) {
+                    Some(b'\n') => end + 2,
+                    Some(_) => end + 1,
+                    // wait for the next chunk to know whether "\r\n" is coming
+                    None => break,
+                },
+                _ => end + 1,
+            };
+
+            let line = String::from_utf8_lossy(&self.buffer[start..end]).into_owned();
+            if let Some(event) = self.process_line(&line) {
+                events.push(event);
+            }
+
+            start = next;
+        }
+
+        self.buffer.drain(..start);
+
+        events
+    }
+
+    /// Flushes an event that was not terminated by a blank line
+    pub fn finish(&mut self) -> Option<Event> {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        if buffer.last() == Some(&b'\r') {
+            buffer.pop();
+        }
+
+        if !buffer.is_empty() {
+            let line = String::from_utf8_lossy(&buffer).into_owned();
+            if let Some(event) = self.process_line(&line) {
+                return Some(event);
+            }
+        }
+
+        self.dispatch()
+    }
+
+    fn process_line(&mut self, line: &str) -> Option<Event> {
+        if line.is_empty() {
+            return self.dispatch();
+        }
+
+        if line.starts_with(':') {
+            return None;
+        }
+
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+
+        match field {
+            "data" => {
+                let data = self.data.get_or_insert_with(String::new);
+                if !data.is_empty() {
+                    data.push('\n');
+                }
+                data.push_str(value);
+            }
+            "event" => self.event = Some(value.to_owned()),
+            _ => {}
+        }
+
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<Event> {
+        let event = self.event.take();
+
+        self.data.take().map(|data| Event { event, data })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Chunk {
+    Delta(Delta),
+    Done,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Delta {
+    pub content: Option<String>,
+    pub tool_calls: Vec<ToolCallDelta>,
+    pub finish_reason: Option<String>,
+    /// Sent in a chunk of its own when `include_usage` is requested
+    pub prompt_tokens: Option<u64>,
+    pub completion_tokens: Option<u64>,
+}
+
+/// A fragment of a tool call; fragments with the same index belong together
+#[derive(Debug, Default, PartialEq)]
+pub struct ToolCallDelta {
+    pub index: usize,
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub arguments: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CompletionError {
+    /// The API reported an error inside the stream
+    Api(String),
+    /// The event could not be parsed as a completion chunk
+    Malformed(String),
+}
+
+impl fmt::Display for CompletionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompletionError::Api(message) => write!(f, "API error: {message}"),
+            CompletionError::Malformed(data) => write!(f, "malformed completion chunk: {data}"),
+        }
+    }
+}
+
+impl std::error::Error for CompletionError {}
+
+/// Decodes a streamed OpenAI-style chat completion into typed chunks
+#[derive(Default)]
+pub struct CompletionDecoder {
+    decoder: Decoder,
+}
+
+impl CompletionDecoder {
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<Result<Chunk, CompletionError>> {
+        self.decoder.feed(bytes).iter().map(parse_chunk).collect()
+    }
+
+    pub fn finish(&mut self) -> Option<Result<Chunk, CompletionError>> {
+        self.decoder.finish().as_ref().map(parse_chunk)
+    }
+}
+
+fn parse_chunk(event: &Event) -> Result<Chunk, CompletionError> {
+    if event.data.trim() == "[DONE]" {
+        return Ok(Chunk::Done);
+    }
+
+    let value: json::Value =
+        json::from_str(&event.data).map_err(|_| CompletionError::Malformed(event.data.clone()))?;
+
+    if let Some(error) = value.get("error") {
+        let message = error["message"]
+            .as_str()
+            .map(str::to_owned)
+            .unwrap_or_else(|| error.to_string());
+        return Err(CompletionError::Api(message));
+    }
+
+    let choice = &value["choices"][0];
+
+    let tool_calls = choice["delta"]["tool_calls"]
+        .as_array()
+        .map(|tool_calls| {
+            tool_calls
+                .iter()
+                .map(|tool_call| ToolCallDelta {
+                    index: tool_call["index"].as_u64().unwrap_or(0) as usize,
+                    id: tool_call["id"].as_str().map(str::to_owned),
+                    name: tool_call["function"]["name"].as_str().map(str::to_owned),
+                    arguments: tool_call["function"]["arguments"]
+                        .as_str()
+                        .map(str::to_owned),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(Chunk::Delta(Delta {
+        content: choice["delta"]["content"].as_str().map(str::to_owned),
+        tool_calls,
+        finish_reason: choice["finish_reason"].as_str().map(str::to_owned),
+        prompt_tokens: value["usage"]["prompt_tokens"].as_u64(),
+        completion_tokens: value["usage"]["completion_tokens"].as_u64(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(data: &str) -> Event {
+        Event {
+            event: None,
+            data: data.to_owned(),
+        }
+    }
+
+    fn delta(content: &str) -> Result<Chunk, CompletionError> {
+        Ok(Chunk::Delta(Delta {
+            content: Some(content.to_owned()),
+            ..Default::default()
+        }))
+    }
+
+    #[test]
+    fn decodes_events_split_across_chunks() {
+        let mut decoder = Decoder::default();
+
+        assert_eq!(decoder.feed(b"data: hel"), vec![]);
+        assert_eq!(decoder.feed(b"lo\n"), vec![]);
+        assert_eq!(
+            decoder.feed(b"\ndata: world\n\n"),
+            vec![event("hello"), event("world")]
+        );
+    }
+
+    #[test]
+    fn decodes_multibyte_characters_split_across_chunks() {
+        let mut decoder = Decoder::default();
+        let bytes = "data: こんにちは\n\n".as_bytes();
+
+        assert_eq!(decoder.feed(&bytes[..8]), vec![]);
+        assert_eq!(decoder.feed(&bytes[8..]), vec![event("こんにちは")]);
+    }
+
+    #[test]
+    fn joins_multi_line_data() {
+        let mut decoder = Decoder::default();
+
+        assert_eq!(
+            decoder.feed(b"data: first\ndata: second\n\n"),
+            vec![event("first\nsecond")]
+        );
+    }
+
+    #[test]
+    fn handles_crlf_and_cr_line_endings() {
+        let mut decoder = Decoder::default();
+
+        assert_eq!(decoder.feed(b"data: a\r"), vec![]);
+        assert_eq!(decoder.feed(b"\n\r\n"), vec![event("a")]);
+        assert_eq!(
+            decoder.feed(b"data: b\r\rdata: c\r\n\r\n"),
+            vec![event("b"), event("c")]
+        );
+    }
+
+    #[test]
+    fn ignores_comments_and_unknown_fields() {
+        let mut decoder = Decoder::default();
+
+        assert_eq!(
+            decoder.feed(b": keep-alive\nid: 1\nretry: 10\nevent: message\ndata:x\n\n"),
+            vec![Event {
+                event: Some("message".to_owned()),
+                data: "x".to_owned(),
+            }]
+        );
+        assert_eq!(decoder.feed(b": only a comment\n\n"), vec![]);
+    }
+
+    #[test]
+    fn finish_flushes_unterminated_event() {
+        let mut decoder = Decoder::default();
+
+        assert_eq!(decoder.feed(b"data: tail"), vec![]);
+        assert_eq!(decoder.finish(), Some(event("tail")));
+        assert_eq!(decoder.finish(), None);
+    }
+
+    #[test]
+    fn decodes_completion_chunks() {
+        let mut decoder = CompletionDecoder::default();
+        let body = concat!(
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"},\"finish_reason\":null}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{},\"finish_reason\":\"stop\"}]}\n\n",
+            "data: [DONE]\n\n",
+        );
+
+        assert_eq!(
+            decoder.feed(body.as_bytes()),
+            vec![
+                delta("Hi"),
+                Ok(Chunk::Delta(Delta {
+                    finish_reason: Some("stop".to_owned()),
+                    ..Default::default()
+                })),
+                Ok(Chunk::Done),
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_tool_call_fragments() {
+        let mut decoder = CompletionDecoder::default();
+        let body = concat!(
+            "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"id\":\"call_1\",",
+            "\"function\":{\"name\":\"list_reminders\",\"arguments\":\"{\"}}]}}]}\n\n",
+        );
+
+        assert_eq!(
+            decoder.feed(body.as_bytes()),
+            vec![Ok(Chunk::Delta(Delta {
+                tool_calls: vec![ToolCallDelta {
+                    index: 0,
+                    id: Some("call_1".to_owned()),
+                    name: Some("list_reminders".to_owned()),
+                    arguments: Some("{".to_owned()),
+                }],
+                ..Default::default()
+            }))]
+        );
+    }
+
+    #[test]
+    fn reports_api_errors_and_malformed_chunks() {
+        let mut decoder = CompletionDecoder::default();
+        let body = "data: {\"error\":{\"message\":\"rate limited\"}}\n\ndata: {oops\n\n";
+
+        assert_eq!(
+            decoder.feed(body.as_bytes()),
+            vec![
+                Err(CompletionError::Api("rate limited".to_owned())),
+                Err(CompletionError::Malformed("{oops".to_owned())),
+            ]
+        );
+    }
+}
@@ -0,0 +1,282 @@
+use chrono::TimeZone;
+use chrono_tz::Asia::Tokyo;
+use poise::serenity_prelude::{self as serenity, json};
+use std::sync::Arc;
+
+use crate::chat::Origin;
+use crate::db::Db;
+use crate::llm::Tool;
+use crate::remind::Remind;
+use crate::voice::Voice;
+use crate::Error;
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M";
+/// Limits on what the LLM can save about a user
+const MAX_MEMORIES: usize = 50;
+const MAX_MEMORY_LENGTH: usize = 500;
+
+/// Functions the LLM can call while answering a message
+pub struct Tools {
+    db: Arc<Db>,
+    remind: Remind,
+    voice: Arc<Voice>,
+}
+
+pub fn build_tools(db: Arc<Db>, remind: Remind, voice: Arc<Voice>) -> Tools {
+    Tools { db, remind, voice }
+}
+
+impl Tools {
+    pub fn definitions(&self) -> Vec<Tool> {
+        let now = chrono::Utc::now().with_timezone(&Tokyo).format(TIME_FORMAT);
+
+        vec![
+            function(
+                "create_reminder",
+                &format!("Create a reminder for the user. The current time is {now} (JST)."),
+                json::json!({
+                    "time": {
+                        "type": "string",
+                        "description": "When to remind in JST, formatted as YYYY-MM-DD HH:MM",
+                    },
+                    "content": {
+                        "type": "string",
+                        "description": "What to remind the user of",
+                    },
+                }),
+                &["time", "content"],
+            ),
+            function(
+                "list_reminders",
+                "List the pending reminders of the user",
+                json::json!({}),
+                &[],
+            ),
+            function(
+                "cancel_reminder",
+                "Cancel a reminder of the user by its id",
+                json::json!({
+                    "id": {
+                        "type": "string",
+                        "description": "The id returned by list_reminders",
+                    },
+                }),
+                &["id"],
+            ),
+            function(
+                "save_memory",
+                "Remember a fact about the user for future conversations, such as their preferences. Use it when the user asks you to remember something.",
+                json::json!({
+                    "content": {
+                        "type": "string",
+                        "description": "The fact to remember, written in a single sentence about the user",
+                    },
+                }),
+                &["content"],
+            ),
+            function(
+                "get_current_speaker",
+                "Get the VOICEVOX speaker used to read the user's messages aloud",
+                json::json!({}),
+                &[],
+            ),
+            function(
+                "list_speakers",
+                "List the available VOICEVOX speakers by id",
+                json::json!({}),
+                &[],
+            ),
+            function(
+                "get_server_info",
+                "Get information about the Discord server and channel of the conversation",
+                json::json!({}),
+                &[],
+            ),
+        ]
+    }
+
+    /// Runs a tool and returns its result as JSON.
+    /// Failures are reported to the LLM instead of aborting the reply.
+    pub async fn call(
+        &self,
+        ctx: &serenity::Context,
+        origin: &Origin,
+        name: &str,
+        arguments: &str,
+    ) -> String {
+        let arguments = if arguments.trim().is_empty() {
+            "{}"
+        } else {
+            arguments
+        };
+        let result = match json::from_str::<json::Value>(arguments) {
+            Ok(arguments) => self.dispatch(ctx, origin, name, &arguments).await,
+            Err(e) => Err(e.into()),
+        };
+
+        match result {
+            Ok(value) => value.to_string(),
+            Err(e) => {
+                log::warn!("Tool {name} failed: {e}");
+                json::json!({ "error": e.to_string() }).to_string()
+            }
+        }
+    }
+
+    async fn dispatch(
+        &self,
+        ctx: &serenity::Context,
+        origin: &Origin,
+        name: &str,
+        arguments: &json::Value,
+    ) -> Result<json::Value, Error> {
+        let user_id = origin.user_id.get();
+
+        match name {
+            "create_reminder" => {
+                let time = arguments["time"].as_str().ok_or("missing time")?;
+                let content = arguments["content"].as_str().ok_or("missing content")?;
+
+                let naive = chrono::NaiveDateTime::parse_from_str(time, TIME_FORMAT)?;
+                let remind_at = Tokyo
+                    .from_local_datetime(&naive)
+                    .single()
+                    .ok_or("Invalid date or time")?;
+
+                if remind_at < chrono::Utc::now() {
+                    return Err("the time is in the past".into());
+                }
+
+                self.remind
+                    .create_reminder(
+                        user_id,
+                        origin.channel_id.get(),
+                        origin.guild_id.map(|guild_id| guild_id.get()),
+                        content.to_owned(),
+                        remind_at.to_utc(),
+                    )
+                    .await?;
+
+                Ok(json::json!({
+                    "time": remind_at.format(TIME_FORMAT).to_string(),
+                    "content": content,
+                }))
+            }
+            "list_reminders" => {
+                let reminders = self.remind.list_reminders(user_id).await?;
+                let reminders = reminders
+                    .iter()
+                    .map(|reminder| {
+                        let remind_at = chrono::DateTime::from_timestamp_millis(
+                            reminder.get_datetime("remind_at")?.timestamp_millis(),
+                        )
+                        .ok_or("Invalid date or time")?
+                        .with_timezone(&Tokyo);
+
+                        Ok(json::json!({
+                            "id": reminder.get_object_id("_id")?.to_hex(),
+                            "time": remind_at.format(TIME_FORMAT).to_string(),
+                            "content": reminder.get_str("content")?,
+                        }))
+                    })
+                    .collect::<Result<Vec<_>, Error>>()?;
+
+                Ok(json::json!(reminders))
+            }
+            "cancel_reminder" => {
+                let id = arguments["id"].as_str().ok_or("missing id")?;
+                let cancelled = self.remind.cancel_reminder(user_id, id).await?;
+
+                Ok(json::json!({ "cancelled": cancelled }))
+            }
+            "save_memory" => {
+                let content = memory_content(arguments)?;
+                if self.db.get_memories(user_id).await?.len() >= MAX_MEMORIES {
+                    return Err(format!(
+                        "the user already has {MAX_MEMORIES} memories; ask them to forget some with /memory forget"
+                    )
+                    .into());
+                }
+
+                self.db.add_memory(user_id, content).await?;
+
+                Ok(json::json!({ "saved": content }))
+            }
+            "get_current_speaker" => {
+                let speaker_id = self.voice.get_vc(user_id).await?;
+                let speakers = self.voice.get_vcs().await?;
+
+                Ok(json::json!({
+                    "id": speaker_id,
+                    "speaker": speakers.get(&speaker_id),
+                }))
+            }
+            "list_speakers" => Ok(json::to_value(self.voice.get_vcs().await?)?),
+            "get_server_info" => {
+                let guild = origin.guild_id.and_then(|guild_id| {
+                    ctx.cache.guild(guild_id).map(|guild| {
+                        json::json!({
+                            "name": guild.name,
+                            "member_count": guild.member_count,
+                            "created_at": guild.id.created_at().to_rfc3339(),
+                        })
+                    })
+                });
+                let channel = origin.channel_id.name(ctx).await.unwrap_or_default();
+
+                Ok(json::json!({
+                    "server": guild,
+                    "channel": channel,
+                    "now": chrono::Utc::now().with_timezone(&Tokyo).to_rfc3339(),
+                }))
+            }
+            _ => Err(format!("unknown tool {name}").into()),
+        }
+    }
+}
+
+fn function(name: &str, description: &str, properties: json::Value, required: &[&str]) -> Tool {
+    Tool {
+        name: name.to_owned(),
+        description: description.to_owned(),
+        parameters: json::json!({
+            "type": "object",
+            "properties": properties,
+            "required": required,
+        }),
+    }
+}
+
+/// The fact given to save_memory, trimmed and checked for length
+fn memory_content(arguments: &json::Value) -> Result<&str, Error> {
+    let content = arguments["content"]
+        .as_str()
+        .map(str::trim)
+        .filter(|content| !content.is_empty())
+        .ok_or("missing content")?;
+
+    if content.chars().count() > MAX_MEMORY_LENGTH {
+        return Err(format!("the memory is longer than {MAX_MEMORY_LENGTH} characters").into());
+    }
+
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_memory_content() {
+        assert_eq!(
+            memory_content(&json::json!({ "content": "  Likes tea\n" })).unwrap(),
+            "Likes tea"
+        );
+        assert!(memory_content(&json::json!({ "content": " " })).is_err());
+        assert!(memory_content(&json::json!({})).is_err());
+        assert!(
+            memory_content(&json::json!({ "content": "あ".repeat(MAX_MEMORY_LENGTH + 1) }))
+                .is_err()
+        );
+    }
+}
@@ -3,31 +3,43 @@ use poise::CreateReply;
 use regex::Regex;
 use songbird::{input::Input, tracks::TrackHandle, Call};
 use tokio::sync::Mutex;
-use url::form_urlencoded;
 
 use std::collections::BTreeMap;
 use std::env::var;
 use std::sync::Arc;
 
+use mongodb::bson::Document;
+
 use crate::db::Db;
+use crate::session::Sessions;
+use crate::voicevox::{build_voicevox, Voicevox};
 use crate::{Context, Error};
 
 const MESSAGE_READ_MAX_LENGTH: usize = 1000;
 const CONNECTED_MESSAGE: &str = "お待たせ！";
 const DEFAULT_SPEAKER_ID: u8 = 8;
+const ENGINE_DOWN_MESSAGE: &str =
+    "VOICEVOX engine seems to be down. Messages will not be read aloud for a while.";
 
 pub struct Voice {
-    http_client: Arc<reqwest::Client>,
-    voicevox_api_url: String,
-    subscribgin_chanel_id: serenity::ChannelId,
+    voicevox: Voicevox,
+    subscribgin_chanel_id: Option<serenity::ChannelId>,
+    sessions: Sessions,
     db: Arc<Db>,
 }
 
 pub fn build_voice(http_client: Arc<reqwest::Client>, db: Arc<Db>) -> Result<Voice, Error> {
     Ok(Voice {
-        http_client,
-        voicevox_api_url: var("VOICEVOX_API_URL")?,
-        subscribgin_chanel_id: serenity::ChannelId::new(var("SUBSCRIBING_CHANNEL_ID")?.parse()?),
+        voicevox: build_voicevox(
+            http_client,
+            var("VOICEVOX_API_URL")?,
+            var("VOICEVOX_FALLBACK_API_URL").ok(),
+        ),
+        subscribgin_chanel_id: match var("SUBSCRIBING_CHANNEL_ID") {
+            Ok(channel_id) => Some(serenity::ChannelId::new(channel_id.parse()?)),
+            Err(_) => None,
+        },
+        sessions: Sessions::default(),
         db,
     })
 }
@@ -58,16 +70,21 @@ impl Voice {
             .expect("Songbird Voice client placed in at initialisation.")
             .clone();
 
-        let _ = manager.join(guild_id, connect_to).await;
+        if manager.join(guild_id, connect_to).await.is_ok() {
+            self.sessions.start(guild_id, connect_to, ctx.channel_id());
+            self.db
+                .save_voice_session(guild_id.get(), connect_to.get(), ctx.channel_id().get())
+                .await?;
+        }
         let _ = ctx.reply("connected").await;
 
         if let Some(handler_lock) = manager.get(guild_id) {
-            self.play_phrase(
-                handler_lock,
-                &ctx.author().id,
-                &CONNECTED_MESSAGE.to_owned(),
-            )
-            .await?;
+            if let Err(e) = self
+                .play_phrase(handler_lock, &ctx.author().id, CONNECTED_MESSAGE)
+                .await
+            {
+                log::error!("Failed to play connected message: {e}");
+            }
         }
 
         Ok(())
@@ -82,6 +99,9 @@ impl Voice {
             .clone();
 
         if manager.get(guild_id).is_some() {
+            self.sessions.end(guild_id);
+            self.db.remove_voice_session(guild_id.get()).await?;
+
             if (manager.remove(guild_id).await).is_err() {
                 let _ = ctx.reply("Failed to leave vc").await;
             }
@@ -99,7 +119,16 @@ impl Voice {
         ctx: &serenity::Context,
         message: &serenity::Message,
     ) -> Result<(), Error> {
-        if message.channel_id != self.subscribgin_chanel_id {
+        let Some(guild_id) = message.guild_id else {
+            return Ok(());
+        };
+        let Some(session) = self.sessions.get(guild_id) else {
+            return Ok(());
+        };
+
+        if message.channel_id != session.text_channel_id
+            && Some(message.channel_id) != self.subscribgin_chanel_id
+        {
             return Ok(());
         }
 
@@ -117,56 +146,195 @@ impl Voice {
             .expect("Songbird Voice client placed in at initialisation.")
             .clone();
 
-        if let Some(handler_lock) = manager.get(message.guild_id.expect("failed to get guild id")) {
+        if let Some(handler_lock) = manager.get(guild_id) {
             // in vc
-            self.play_phrase(handler_lock, &message.author.id, &message.content)
-                .await?;
+            match self
+                .play_phrase(handler_lock, &message.author.id, &message.content)
+                .await
+            {
+                Ok(_) => self.sessions.count_read(guild_id),
+                Err(e) => {
+                    log::error!("Failed to read message aloud: {e}");
+
+                    if self.voicevox.take_warning() {
+                        message.channel_id.say(ctx, ENGINE_DOWN_MESSAGE).await?;
+                    }
+                }
+            }
+        } else {
+            // disconnected without /disconnect_vc, e.g. kicked from the channel
+            self.sessions.end(guild_id);
+            self.db.remove_voice_session(guild_id.get()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Rejoins the voice sessions that were active before the bot restarted.
+    /// Sessions whose voice channel has no humans left are dropped.
+    pub async fn restore_sessions(&self, ctx: &serenity::Context) -> Result<(), Error> {
+        let manager = songbird::get(ctx)
+            .await
+            .expect("Songbird Voice client placed in at initialisation.")
+            .clone();
+
+        // a broken session must not keep the others from being restored
+        for session in self.db.get_voice_sessions().await? {
+            let (guild_id, voice_channel_id, text_channel_id) = match parse_session(&session) {
+                Ok(ids) => ids,
+                Err(e) => {
+                    log::error!("Skipping malformed voice session {session}: {e}");
+                    continue;
+                }
+            };
+
+            // the voice states of GUILD_CREATE usually come without members,
+            // and users that can't be found are not counted as humans
+            let humans_present = ctx.cache.guild(guild_id).is_some_and(|guild| {
+                guild.voice_states.values().any(|voice_state| {
+                    let is_bot = voice_state
+                        .member
+                        .as_ref()
+                        .or_else(|| guild.members.get(&voice_state.user_id))
+                        .map(|member| member.user.bot)
+                        .or_else(|| ctx.cache.user(voice_state.user_id).map(|user| user.bot));
+
+                    voice_state.channel_id == Some(voice_channel_id)
+                        && voice_state.user_id != ctx.cache.current_user().id
+                        && is_bot == Some(false)
+                })
+            });
+
+            if !humans_present {
+                if let Err(e) = self.db.remove_voice_session(guild_id.get()).await {
+                    log::error!("Failed to remove voice session in guild {guild_id}: {e}");
+                }
+                continue;
+            }
+
+            let handler_lock = match manager.join(guild_id, voice_channel_id).await {
+                Ok(handler_lock) => handler_lock,
+                Err(e) => {
+                    log::error!("Failed to rejoin vc in guild {guild_id}: {e}");
+                    if let Err(e) = self.db.remove_voice_session(guild_id.get()).await {
+                        log::error!("Failed to remove voice session in guild {guild_id}: {e}");
+                    }
+                    continue;
+                }
+            };
+            self.sessions
+                .start(guild_id, voice_channel_id, text_channel_id);
+
+            if let Err(e) = text_channel_id.say(ctx, CONNECTED_MESSAGE).await {
+                log::error!("Failed to send connected message in guild {guild_id}: {e}");
+            }
+            if let Err(e) = self
+                .play_phrase_as(handler_lock, DEFAULT_SPEAKER_ID, CONNECTED_MESSAGE)
+                .await
+            {
+                log::error!("Failed to play connected message: {e}");
+            }
         }
 
         Ok(())
     }
 
+    /// Reads the text aloud if the user is in the vc the bot is connected to.
+    /// Returns whether the text was played.
+    pub async fn read_aloud(
+        &self,
+        ctx: &serenity::Context,
+        guild_id: serenity::GuildId,
+        user_id: UserId,
+        text: &str,
+    ) -> Result<bool, Error> {
+        let manager = songbird::get(ctx)
+            .await
+            .expect("Songbird Voice client placed in at initialisation.")
+            .clone();
+
+        let Some(handler_lock) = manager.get(guild_id) else {
+            return Ok(false);
+        };
+
+        let bot_channel_id = handler_lock.lock().await.current_channel();
+        let user_channel_id = ctx.cache.guild(guild_id).and_then(|guild| {
+            guild
+                .voice_states
+                .get(&user_id)
+                .and_then(|voice_state| voice_state.channel_id)
+        });
+
+        match (bot_channel_id, user_channel_id) {
+            (Some(bot_channel_id), Some(user_channel_id))
+                if bot_channel_id == user_channel_id.into() =>
+            {
+                self.play_phrase(handler_lock, &user_id, text).await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
     /// callable when already in vc
     async fn play_phrase(
         &self,
         handler_lock: Arc<Mutex<Call>>,
         user_id: &UserId,
-        text: &String,
+        text: &str,
     ) -> Result<TrackHandle, Error> {
         let speaker_id = self.get_vc(user_id.get()).await?;
-        let text = form_urlencoded::byte_serialize(text.as_bytes()).collect::<String>();
-
-        let audio_query_url = format!(
-            "{}/audio_query?text={}&speaker={}",
-            &self.voicevox_api_url, text, speaker_id
-        );
-
-        let audio_query = self
-            .http_client
-            .post(audio_query_url)
-            .send()
-            .await?
-            .text()
-            .await?;
-
-        let synthesis_url = format!(
-            "{}/synthesis?&speaker={}",
-            &self.voicevox_api_url, speaker_id
-        );
-        let audio: Input = self
-            .http_client
-            .post(synthesis_url)
-            .header("Content-Type", "application/json")
-            .body(audio_query)
-            .send()
-            .await?
-            .bytes()
-            .await?
-            .into();
+
+        self.play_phrase_as(handler_lock, speaker_id, text).await
+    }
+
+    /// callable when already in vc
+    async fn play_phrase_as(
+        &self,
+        handler_lock: Arc<Mutex<Call>>,
+        speaker_id: u8,
+        text: &str,
+    ) -> Result<TrackHandle, Error> {
+        let audio: Input = self.voicevox.synthesis(text, speaker_id).await?.into();
 
         let mut handler = handler_lock.lock().await;
 
-        Ok(handler.play_input(audio))
+        Ok(handler.enqueue_input(audio).await)
+    }
+
+    pub async fn vc_status(&self, ctx: Context<'_>) -> Result<(), Error> {
+        let guild_id = ctx.guild_id().expect("failed to get guild id");
+
+        let Some(session) = self.sessions.get(guild_id) else {
+            ctx.reply("Not in a voice channel").await?;
+            return Ok(());
+        };
+
+        let manager = songbird::get(ctx.serenity_context())
+            .await
+            .expect("Songbird Voice client placed in at initialisation.")
+            .clone();
+
+        let queue_depth = match manager.get(guild_id) {
+            Some(handler_lock) => handler_lock.lock().await.queue().len(),
+            None => 0,
+        };
+
+        let uptime = (chrono::Utc::now() - session.started_at).num_seconds();
+
+        ctx.reply(format!(
+            "Voice channel: <#{}>\nReading channel: <#{}>\nUptime: {}h {}m {}s\nQueue: {}\nMessages read: {}",
+            session.voice_channel_id,
+            session.text_channel_id,
+            uptime / 3600,
+            uptime % 3600 / 60,
+            uptime % 60,
+            queue_depth,
+            session.messages_read,
+        ))
+        .await?;
+
+        Ok(())
     }
 
     pub async fn show_vc(&self, ctx: Context<'_>) -> Result<(), Error> {
@@ -183,7 +351,7 @@ impl Voice {
         Ok(())
     }
 
-    async fn get_vc(&self, user_id: u64) -> Result<u8, Error> {
+    pub async fn get_vc(&self, user_id: u64) -> Result<u8, Error> {
         let speaker_id = match self.db.get_speaker(user_id).await? {
             Some(speaker_id) => speaker_id,
             None => {
@@ -243,15 +411,7 @@ impl Voice {
     }
 
     pub async fn get_vcs(&self) -> Result<BTreeMap<u8, json::Value>, Error> {
-        let speakers_query_url = format!("{}/speakers", self.voicevox_api_url);
-
-        let speakers_str = self
-            .http_client
-            .get(speakers_query_url)
-            .send()
-            .await?
-            .text()
-            .await?;
+        let speakers_str = self.voicevox.speakers().await?;
 
         let speakers: json::Value = json::from_str(&speakers_str)?;
         let speakers = speakers.as_array().expect("failed to parse speakers");
@@ -294,6 +454,17 @@ impl Voice {
     }
 }
 
+/// The guild, voice channel and text channel of a stored session
+fn parse_session(
+    session: &Document,
+) -> Result<(serenity::GuildId, serenity::ChannelId, serenity::ChannelId), Error> {
+    Ok((
+        serenity::GuildId::new(session.get_str("guild_id")?.parse()?),
+        serenity::ChannelId::new(session.get_str("voice_channel_id")?.parse()?),
+        serenity::ChannelId::new(session.get_str("text_channel_id")?.parse()?),
+    ))
+}
+
 /// Connect to the voice channel the user is in
 #[poise::command(slash_command)]
 pub async fn connect_vc(ctx: Context<'_>) -> Result<(), Error> {
@@ -306,6 +477,12 @@ pub async fn disconnect_vc(ctx: Context<'_>) -> Result<(), Error> {
     ctx.data().voice.disconnect_vc(ctx).await
 }
 
+/// Show the voice session of this server
+#[poise::command(slash_command)]
+pub async fn vc_status(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.data().voice.vc_status(ctx).await
+}
+
 /// Show vc
 #[poise::command(slash_command)]
 pub async fn show_vc(ctx: Context<'_>) -> Result<(), Error> {
@@ -0,0 +1,269 @@
+use std::sync::{Arc, Mutex};
+use tokio::time::{sleep, timeout, Duration, Instant};
+use url::form_urlencoded;
+
+use crate::Error;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Added to the synthesis timeout per character, as CPU engines take longer
+/// for long messages
+const TIMEOUT_PER_CHARACTER: Duration = Duration::from_millis(20);
+/// For all attempts on all engines, well under the voice handler timeout in
+/// main.rs so that the failure is still recorded
+const CALL_DEADLINE: Duration = Duration::from_secs(45);
+const MAX_ATTEMPTS: u32 = 3;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(200);
+const BREAKER_THRESHOLD: u32 = 3;
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// Client for the VOICEVOX engine with retries, an optional fallback engine
+/// and a circuit breaker that stops hammering an engine that is down.
+pub struct Voicevox {
+    http_client: Arc<reqwest::Client>,
+    api_urls: Vec<String>,
+    breaker: Mutex<Breaker>,
+}
+
+#[derive(Default)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+    warned: bool,
+}
+
+pub fn build_voicevox(
+    http_client: Arc<reqwest::Client>,
+    api_url: String,
+    fallback_api_url: Option<String>,
+) -> Voicevox {
+    let api_urls = std::iter::once(api_url).chain(fallback_api_url).collect();
+
+    Voicevox {
+        http_client,
+        api_urls,
+        breaker: Mutex::new(Breaker::default()),
+    }
+}
+
+impl Voicevox {
+    pub async fn synthesis(&self, text: &str, speaker_id: u8) -> Result<Vec<u8>, Error> {
+        let request_timeout = REQUEST_TIMEOUT + TIMEOUT_PER_CHARACTER * text.chars().count() as u32;
+        let text = form_urlencoded::byte_serialize(text.as_bytes()).collect::<String>();
+
+        self.call(|client, api_url| {
+            let audio_query_url = format!("{api_url}/audio_query?text={text}&speaker={speaker_id}");
+            let synthesis_url = format!("{api_url}/synthesis?&speaker={speaker_id}");
+
+            async move {
+                let audio_query = client
+                    .post(audio_query_url)
+                    .timeout(request_timeout)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .text()
+                    .await?;
+
+                let audio = client
+                    .post(synthesis_url)
+                    .timeout(request_timeout)
+                    .header("Content-Type", "application/json")
+                    .body(audio_query)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .bytes()
+                    .await?;
+
+                Ok(audio.to_vec())
+            }
+        })
+        .await
+    }
+
+    pub async fn speakers(&self) -> Result<String, Error> {
+        self.call(|client, api_url| async move {
+            Ok(client
+                .get(format!("{api_url}/speakers"))
+                .timeout(REQUEST_TIMEOUT)
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?)
+        })
+        .await
+    }
+
+    /// Returns true once each time the breaker opens, so that callers can post
+    /// a single warning per outage.
+    pub fn take_warning(&self) -> bool {
+        self.breaker
+            .lock()
+            .expect("breaker lock poisoned")
+            .take_warning()
+    }
+
+    async fn call<T, F, Fut>(&self, request: F) -> Result<T, Error>
+    where
+        F: Fn(Arc<reqwest::Client>, String) -> Fut,
+        Fut: std::future::Future<Output = Result<T, Error>>,
+    {
+        if self.is_open() {
+            return Err("VOICEVOX engine is unavailable".into());
+        }
+
+        let result = match timeout(CALL_DEADLINE, self.try_engines(request)).await {
+            Ok(result) => result,
+            Err(_) => Err(format!(
+                "VOICEVOX did not respond within {} seconds",
+                CALL_DEADLINE.as_secs()
+            )
+            .into()),
+        };
+
+        match result {
+            Ok(value) => {
+                self.record_success();
+                Ok(value)
+            }
+            Err(e) => {
+                self.record_failure();
+                Err(e)
+            }
+        }
+    }
+
+    /// Tries each engine in turn, retrying with backoff
+    async fn try_engines<T, F, Fut>(&self, request: F) -> Result<T, Error>
+    where
+        F: Fn(Arc<reqwest::Client>, String) -> Fut,
+        Fut: std::future::Future<Output = Result<T, Error>>,
+    {
+        let mut last_error: Error = "no VOICEVOX engine configured".into();
+
+        for api_url in &self.api_urls {
+            let mut backoff = INITIAL_BACKOFF;
+
+            for attempt in 1..=MAX_ATTEMPTS {
+                match request(Arc::clone(&self.http_client), api_url.clone()).await {
+                    Ok(value) => return Ok(value),
+                    Err(e) => {
+                        log::warn!("VOICEVOX request to {api_url} failed (attempt {attempt}): {e}");
+                        last_error = e;
+                    }
+                }
+
+                if attempt < MAX_ATTEMPTS {
+                    sleep(backoff).await;
+                    backoff *= 2;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+
+    fn is_open(&self) -> bool {
+        self.breaker
+            .lock()
+            .expect("breaker lock poisoned")
+            .is_open(Instant::now())
+    }
+
+    fn record_success(&self) {
+        *self.breaker.lock().expect("breaker lock poisoned") = Breaker::default();
+    }
+
+    fn record_failure(&self) {
+        self.breaker
+            .lock()
+            .expect("breaker lock poisoned")
+            .record_failure(Instant::now());
+    }
+}
+
+impl Breaker {
+    fn is_open(&mut self, now: Instant) -> bool {
+        match self.open_until {
+            Some(open_until) if now < open_until => true,
+            Some(_) => {
+                // half-open: let the next request through to probe the engine
+                self.open_until = None;
+                self.failures = BREAKER_THRESHOLD - 1;
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn record_failure(&mut self, now: Instant) {
+        self.failures += 1;
+
+        if self.failures >= BREAKER_THRESHOLD {
+            self.open_until = Some(now + BREAKER_COOLDOWN);
+        }
+    }
+
+    fn take_warning(&mut self) -> bool {
+        if self.open_until.is_some() && !self.warned {
+            self.warned = true;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_after_repeated_failures() {
+        let now = Instant::now();
+        let mut breaker = Breaker::default();
+
+        for _ in 1..BREAKER_THRESHOLD {
+            breaker.record_failure(now);
+            assert!(!breaker.is_open(now));
+            assert!(!breaker.take_warning());
+        }
+        breaker.record_failure(now);
+
+        assert!(breaker.is_open(now));
+        assert!(breaker.is_open(now + BREAKER_COOLDOWN - Duration::from_secs(1)));
+        assert!(breaker.take_warning());
+        assert!(!breaker.take_warning());
+    }
+
+    #[test]
+    fn probes_once_after_cooldown() {
+        let now = Instant::now();
+        let mut breaker = Breaker::default();
+        for _ in 0..BREAKER_THRESHOLD {
+            breaker.record_failure(now);
+        }
+
+        let later = now + BREAKER_COOLDOWN;
+        assert!(!breaker.is_open(later));
+
+        // a failed probe opens it again at once
+        breaker.record_failure(later);
+        assert!(breaker.is_open(later));
+    }
+
+    #[test]
+    fn closes_after_success() {
+        let now = Instant::now();
+        let voicevox = build_voicevox(Arc::new(reqwest::Client::new()), String::new(), None);
+        for _ in 0..BREAKER_THRESHOLD {
+            voicevox.breaker.lock().unwrap().record_failure(now);
+        }
+
+        voicevox.record_success();
+
+        assert!(!voicevox.is_open());
+        assert!(!voicevox.take_warning());
+    }
+}